@@ -2,7 +2,7 @@ use std::{fs::File, io::BufReader};
 
 use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
 use indicatif::{ProgressBar, ProgressDrawTarget};
-use logmine_rs::{clusterer::ClustererOptions, pattern::Pattern};
+use logmine_rs::{clusterer::ClustererOptions, input::LineDecoder, pattern::Pattern};
 use rayon::ThreadPoolBuilder;
 use regex::Regex;
 
@@ -48,9 +48,11 @@ pub fn criterion_benchmark(c: &mut Criterion) {
                             ..Default::default()
                         },
                         f,
+                        &LineDecoder::default(),
                         progress,
                         split_regex,
-                    ));
+                    ))
+                    .unwrap();
                 },
                 criterion::BatchSize::SmallInput,
             )
@@ -75,10 +77,12 @@ pub fn criterion_benchmark(c: &mut Criterion) {
                     Default::default(),
                     2,
                     f,
+                    &LineDecoder::default(),
                     progress,
                     split_regex,
                     pool,
-                ));
+                ))
+                .unwrap();
             },
             criterion::BatchSize::SmallInput,
         );
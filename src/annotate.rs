@@ -0,0 +1,219 @@
+use std::{
+    io::{self, Write},
+    str::FromStr,
+};
+
+use regex::Regex;
+
+use crate::{
+    candidates::LengthIndex, clusterer::Cluster, pattern::Pattern, scoring, tokenizer::Tokenizer,
+};
+
+/// Assigns lines to the clusters of a finished run, for runs where the cluster
+/// of each line wasn't known while it was processed (such as in
+/// `parallel_clusterer`, where clusters from different threads are merged at
+/// the end). Cluster ids are indexes into the slice of clusters given to
+/// `Annotator::new`.
+///
+/// Merged clusters only keep the representative of one of the clusters they
+/// were merged from, so a line may be assigned to a different cluster than
+/// the one which counted it, or to none at all. The number of lines given
+/// each id can then differ from the count of that cluster.
+pub struct Annotator<'c, T = Regex> {
+    clusters: &'c [Cluster<'static>],
+    /// Lengths of the representatives of `clusters`
+    index: LengthIndex,
+    max_dist: f64,
+    tokenizer: T,
+    pattern_backing_storage: Pattern<'static>,
+}
+
+impl<'c, T: Tokenizer> Annotator<'c, T> {
+    pub fn new(clusters: &'c [Cluster<'static>], max_dist: f64, tokenizer: T) -> Self {
+        Self {
+            clusters,
+            index: LengthIndex::new(clusters.iter().map(|c| c.representative.len())),
+            max_dist,
+            tokenizer,
+            pattern_backing_storage: Default::default(),
+        }
+    }
+
+    /// Id of the first cluster whose representative is within the max
+    /// distance of `line`, the same rule `Clusterer` uses to assign lines. For
+    /// the clusters of a single `Clusterer`, in the order they were created,
+    /// this gives the same id that `Clusterer::process_line` returned for the
+    /// line.
+    pub fn cluster_id(&mut self, line: &str) -> Option<usize> {
+        let mut pattern = std::mem::take(&mut self.pattern_backing_storage).clear_and_reinterpret();
+        pattern.push_line(line, &self.tokenizer);
+
+        self.find(pattern)
+    }
+
+    /// Same as `cluster_id`, for a line of raw bytes which may not be UTF-8
+    /// (see `Clusterer::process_bytes`)
+    pub fn bytes_cluster_id(&mut self, line: &[u8]) -> Option<usize> {
+        let mut pattern = std::mem::take(&mut self.pattern_backing_storage).clear_and_reinterpret();
+        pattern.push_bytes(line, &self.tokenizer);
+
+        self.find(pattern)
+    }
+
+    fn find(&mut self, pattern: Pattern) -> Option<usize> {
+        let (clusters, max_dist) = (self.clusters, self.max_dist);
+        let id = self
+            .index
+            .find(pattern.len(), max_dist, 0..clusters.len(), |&id| {
+                scoring::distance(&clusters[id].representative, &pattern, max_dist) <= max_dist
+            });
+
+        self.pattern_backing_storage = pattern.clear_and_reinterpret();
+
+        id
+    }
+}
+
+/// Layout of the file written by `Annotations`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AnnotationFormat {
+    /// Every input line, prefixed with its cluster id and a tab
+    Lines,
+    /// One `line_no<TAB>cluster_id` row per input line, with line numbers
+    /// starting at 1
+    Index,
+}
+
+impl FromStr for AnnotationFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lines" => Ok(Self::Lines),
+            "index" => Ok(Self::Index),
+            _ => Err(format!(
+                "unknown annotation format {:?}, expected lines or index",
+                s
+            )),
+        }
+    }
+}
+
+/// Writes the cluster id of each input line, in input order. Lines without a
+/// cluster get `-` as their id.
+pub struct Annotations<W> {
+    writer: W,
+    format: AnnotationFormat,
+    line_no: u64,
+}
+
+impl<W: Write> Annotations<W> {
+    pub fn new(writer: W, format: AnnotationFormat) -> Self {
+        Self {
+            writer,
+            format,
+            line_no: 0,
+        }
+    }
+
+    /// Record the cluster id of the next line of input. `line` should include
+    /// its trailing newline, if it had one.
+    pub fn write(&mut self, cluster_id: Option<usize>, line: &[u8]) -> io::Result<()> {
+        self.line_no += 1;
+
+        let id = match cluster_id {
+            Some(id) => id.to_string(),
+            None => "-".to_string(),
+        };
+
+        match self.format {
+            AnnotationFormat::Lines => {
+                write!(self.writer, "{}\t", id)?;
+                self.writer.write_all(line)?;
+                if !line.ends_with(b"\n") {
+                    writeln!(self.writer)?;
+                }
+            }
+            AnnotationFormat::Index => writeln!(self.writer, "{}\t{}", self.line_no, id)?,
+        }
+
+        Ok(())
+    }
+
+    /// Flush and return the underlying writer
+    pub fn into_inner(mut self) -> io::Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use regex::Regex;
+
+    use crate::clusterer::{Clusterer, ClustererOptions};
+
+    use super::{AnnotationFormat, Annotations, Annotator};
+
+    #[test]
+    fn test_second_pass_matches_process_line() {
+        let lines = [
+            "hello 1 y 3",
+            "abc m n q",
+            "hello 1 x 3",
+            "abc m n r",
+            "xyz",
+        ];
+
+        let mut clusterer = Clusterer::new(
+            ClustererOptions::default().with_max_dist(0.5),
+            Regex::new("\\s+").unwrap(),
+        );
+        let first_pass: Vec<_> = lines.iter().map(|l| clusterer.process_line(l)).collect();
+        assert_eq!(first_pass, vec![0, 1, 0, 1, 2]);
+
+        let clusters: Vec<_> = clusterer.take_result().collect();
+        let mut annotator = Annotator::new(&clusters, 0.5, Regex::new("\\s+").unwrap());
+        let second_pass: Vec<_> = lines
+            .iter()
+            .map(|l| annotator.cluster_id(l).unwrap())
+            .collect();
+
+        assert_eq!(second_pass, first_pass);
+    }
+
+    #[test]
+    fn test_bytes_cluster_id() {
+        let mut clusterer = Clusterer::new(
+            ClustererOptions::default().with_max_dist(0.5),
+            Regex::new("\\s+").unwrap(),
+        );
+        let lines: [&[u8]; 3] = [b"a \xff 1", b"a \xff 2", b"b c \xfe"];
+        let first_pass: Vec<_> = lines.iter().map(|l| clusterer.process_bytes(l)).collect();
+        assert_eq!(first_pass, vec![0, 0, 1]);
+
+        let clusters: Vec<_> = clusterer.take_result().collect();
+        let mut annotator = Annotator::new(&clusters, 0.5, Regex::new("\\s+").unwrap());
+        let second_pass: Vec<_> = lines
+            .iter()
+            .map(|l| annotator.bytes_cluster_id(l).unwrap())
+            .collect();
+
+        assert_eq!(second_pass, first_pass);
+        assert_eq!(annotator.bytes_cluster_id(b"x y \xfd z"), None);
+    }
+
+    #[test]
+    fn test_annotation_formats() {
+        let mut lines = Annotations::new(Vec::new(), AnnotationFormat::Lines);
+        lines.write(Some(3), b"a b\n").unwrap();
+        lines.write(None, b"c").unwrap();
+
+        let mut index = Annotations::new(Vec::new(), AnnotationFormat::Index);
+        index.write(Some(3), b"a b\n").unwrap();
+        index.write(None, b"c").unwrap();
+
+        assert_eq!(lines.into_inner().unwrap(), b"3\ta b\n-\tc\n");
+        assert_eq!(index.into_inner().unwrap(), b"1\t3\n2\t-\n");
+    }
+}
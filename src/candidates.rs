@@ -0,0 +1,159 @@
+//! Narrowing down which clusters a pattern could be close enough to, without
+//! comparing it to every cluster.
+//!
+//! Two patterns of lengths `m <= n` have at most `m` items in common, so
+//! their distance is at least `1 - m / n`. Clusters whose representative is
+//! so much shorter or longer than a pattern that this bound is above the
+//! maximum distance can be skipped.
+
+use std::{collections::BTreeMap, ops::Range};
+
+/// Slack for the rounding of the running total in `scoring::distance`, so
+/// that no cluster which could be close enough is ever skipped
+const EPSILON: f64 = 1e-9;
+
+/// Ids of clusters grouped by the length of their representative
+#[derive(Debug, Default, Clone)]
+pub(crate) struct LengthIndex {
+    by_len: BTreeMap<usize, Vec<usize>>,
+}
+
+impl LengthIndex {
+    /// Index the representatives of clusters `0..`, in order
+    pub fn new(lens: impl IntoIterator<Item = usize>) -> Self {
+        let mut index = Self::default();
+        for (id, len) in lens.into_iter().enumerate() {
+            index.insert(len, id);
+        }
+        index
+    }
+
+    /// Add the cluster `id`, which must be greater than every id added so
+    /// far, with a representative of `len` items
+    pub fn insert(&mut self, len: usize, id: usize) {
+        let ids = self.by_len.entry(len).or_default();
+        debug_assert!(ids.last() < Some(&id));
+        ids.push(id);
+    }
+
+    /// Ids among `ids` of the clusters which a pattern of `len` items could
+    /// be within `max_dist` of, in increasing order
+    pub fn candidates(&self, len: usize, max_dist: f64, ids: Range<usize>) -> Candidates<'_> {
+        let (min_len, max_len) = len_bounds(len, max_dist);
+
+        let lists = self
+            .by_len
+            .range(min_len..=max_len)
+            .filter(|(&other_len, _)| could_match(len, other_len, max_dist))
+            .map(|(_, list)| {
+                let start = list.partition_point(|&id| id < ids.start);
+                let end = list.partition_point(|&id| id < ids.end);
+                &list[start..end]
+            })
+            .filter(|list| !list.is_empty())
+            .collect();
+
+        Candidates { lists }
+    }
+
+    /// First of the `candidates` for a pattern of `len` items for which
+    /// `is_close` is true
+    pub fn find(
+        &self,
+        len: usize,
+        max_dist: f64,
+        ids: Range<usize>,
+        is_close: impl FnMut(&usize) -> bool,
+    ) -> Option<usize> {
+        self.candidates(len, max_dist, ids).find(is_close)
+    }
+}
+
+/// Ids from several increasing lists, merged into increasing order
+pub(crate) struct Candidates<'a> {
+    lists: Vec<&'a [usize]>,
+}
+
+impl<'a> Iterator for Candidates<'a> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        let (i, &id) = self
+            .lists
+            .iter()
+            .enumerate()
+            .map(|(i, list)| (i, &list[0]))
+            .min_by_key(|(_, &id)| id)?;
+
+        self.lists[i] = &self.lists[i][1..];
+        if self.lists[i].is_empty() {
+            self.lists.swap_remove(i);
+        }
+
+        Some(id)
+    }
+}
+
+/// Can patterns of these lengths be within `max_dist` of each other
+fn could_match(a: usize, b: usize, max_dist: f64) -> bool {
+    let (shorter, longer) = (a.min(b), a.max(b));
+
+    // `scoring::distance` of two empty patterns is 1
+    let shared = if longer == 0 {
+        0.0
+    } else {
+        shorter as f64 / longer as f64
+    };
+
+    1.0 - shared <= max_dist + EPSILON
+}
+
+/// Range of lengths which includes every length `could_match` accepts for a
+/// pattern of `len` items
+fn len_bounds(len: usize, max_dist: f64) -> (usize, usize) {
+    let ratio = 1.0 - max_dist;
+    if ratio <= 0.0 {
+        return (0, usize::MAX);
+    }
+
+    let min_len = (len as f64 * ratio).floor() as usize;
+    let max_len = (len as f64 / ratio).ceil();
+
+    (
+        min_len.saturating_sub(1),
+        if max_len >= usize::MAX as f64 {
+            usize::MAX
+        } else {
+            max_len as usize + 1
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{could_match, LengthIndex};
+
+    #[test]
+    fn test_candidates() {
+        let index = LengthIndex::new(vec![4, 10, 5, 4, 8, 2]);
+
+        let candidates =
+            |len, max_dist, ids| index.candidates(len, max_dist, ids).collect::<Vec<_>>();
+
+        assert_eq!(candidates(4, 0.0, 0..6), vec![0, 3]);
+        assert_eq!(candidates(4, 0.2, 0..6), vec![0, 2, 3]);
+        assert_eq!(candidates(4, 0.5, 0..6), vec![0, 2, 3, 4, 5]);
+        assert_eq!(candidates(4, 0.5, 1..4), vec![2, 3]);
+        assert_eq!(candidates(4, 1.0, 0..6), vec![0, 1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn test_could_match_is_a_lower_bound() {
+        // lengths whose shared share is exactly 1 - max_dist
+        assert!(could_match(3, 10, 0.7));
+        assert!(could_match(10, 3, 0.7));
+        assert!(!could_match(2, 10, 0.7));
+        assert!(!could_match(0, 0, 0.5));
+        assert!(could_match(0, 0, 1.0));
+    }
+}
@@ -0,0 +1,223 @@
+//! How many lines each thread of `parallel_clusterer::run` reads from the
+//! input while it holds the lock on it
+
+use std::{convert::TryFrom, sync::Arc, time::Duration};
+
+use parking_lot::Mutex;
+
+/// Lines read at a time when no size is given
+pub const DEFAULT_CHUNK_SIZE: usize = 10000;
+
+/// Size of the first chunk with `ChunkSize::Adaptive`, small so that the
+/// first measurements come in quickly
+const INITIAL_ADAPTIVE_SIZE: usize = 1000;
+
+/// Longest time a thread should spend on one chunk, so that snapshots and the
+/// end of a run don't keep the other threads waiting for it
+const TARGET_CHUNK_TIME: Duration = Duration::from_millis(100);
+
+/// Most bytes of lines a thread should hold at once
+const MAX_CHUNK_BYTES: usize = 16 << 20;
+
+#[derive(Debug, Clone)]
+pub enum ChunkSize {
+    /// Always read this many lines
+    Fixed(usize),
+    Adaptive(AdaptiveChunkSize),
+}
+
+impl From<usize> for ChunkSize {
+    fn from(size: usize) -> Self {
+        ChunkSize::Fixed(size)
+    }
+}
+
+/// Chunk sizes chosen at runtime, between `min` and `max` lines, from how long
+/// threads wait for the lock on the input, how long lines are and how long
+/// they take to cluster. Clones share the statistics of the sizes chosen, so
+/// that a clone kept by the caller can report them after a run.
+#[derive(Debug, Clone)]
+pub struct AdaptiveChunkSize {
+    min: usize,
+    max: usize,
+    stats: Arc<Mutex<ChunkStats>>,
+}
+
+/// Sizes of the chunks read by a run
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ChunkStats {
+    pub chunks: u64,
+    pub lines: u64,
+    pub smallest: usize,
+    pub largest: usize,
+    /// Size chosen for the last chunk
+    pub last: usize,
+}
+
+impl AdaptiveChunkSize {
+    pub fn new(min: usize, max: usize) -> Self {
+        let min = min.max(1);
+
+        Self {
+            min,
+            max: max.max(min),
+            stats: Default::default(),
+        }
+    }
+
+    pub fn stats(&self) -> ChunkStats {
+        *self.stats.lock()
+    }
+}
+
+impl ChunkStats {
+    /// Average number of lines per chunk
+    pub fn mean(&self) -> f64 {
+        if self.chunks == 0 {
+            0.0
+        } else {
+            self.lines as f64 / self.chunks as f64
+        }
+    }
+
+    fn record(&mut self, size: usize, lines: usize) {
+        if self.chunks == 0 {
+            self.smallest = size;
+        }
+        self.chunks += 1;
+        self.lines += lines as u64;
+        self.smallest = self.smallest.min(size);
+        self.largest = self.largest.max(size);
+        self.last = size;
+    }
+}
+
+/// What a thread measured while reading and clustering a chunk
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct ChunkMeasurement {
+    /// Time spent waiting for the lock on the input
+    pub lock_wait: Duration,
+    pub lines: usize,
+    pub bytes: usize,
+    /// Time spent clustering the lines
+    pub processing: Duration,
+}
+
+/// Size of the chunks read by one thread
+pub(crate) struct ChunkSizer {
+    size: usize,
+    adaptive: Option<AdaptiveChunkSize>,
+}
+
+impl ChunkSizer {
+    pub fn new(chunk_size: &ChunkSize) -> Self {
+        match chunk_size {
+            ChunkSize::Fixed(size) => Self {
+                size: *size,
+                adaptive: None,
+            },
+            ChunkSize::Adaptive(adaptive) => Self {
+                size: INITIAL_ADAPTIVE_SIZE.clamp(adaptive.min, adaptive.max),
+                adaptive: Some(adaptive.clone()),
+            },
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Choose the size of the next chunk from the measurements of the last
+    /// one. Returns the new size.
+    pub fn update(&mut self, measured: ChunkMeasurement) -> usize {
+        let adaptive = match &self.adaptive {
+            Some(adaptive) if measured.lines > 0 => adaptive,
+            _ => return self.size,
+        };
+        adaptive.stats.lock().record(self.size, measured.lines);
+
+        // waiting on the lock for a large share of the time means that threads
+        // take turns too often, while hardly waiting at all leaves room for
+        // smaller chunks, which balance the work better at the end of a run
+        let mut size = if measured.lock_wait * 10 > measured.processing {
+            self.size.saturating_mul(2)
+        } else if measured.lock_wait * 100 < measured.processing {
+            self.size - self.size / 4
+        } else {
+            self.size
+        };
+
+        let per_line = measured.processing.as_nanos() / measured.lines as u128;
+        if let Some(lines) = TARGET_CHUNK_TIME.as_nanos().checked_div(per_line) {
+            size = size.min(usize::try_from(lines).unwrap_or(usize::MAX));
+        }
+
+        let line_len = measured.bytes / measured.lines;
+        if let Some(lines) = MAX_CHUNK_BYTES.checked_div(line_len) {
+            size = size.min(lines);
+        }
+
+        self.size = size.clamp(adaptive.min, adaptive.max);
+        self.size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{AdaptiveChunkSize, ChunkMeasurement, ChunkSize, ChunkSizer};
+
+    fn measured(
+        lock_wait_ms: u64,
+        lines: usize,
+        bytes: usize,
+        processing_ms: u64,
+    ) -> ChunkMeasurement {
+        ChunkMeasurement {
+            lock_wait: Duration::from_millis(lock_wait_ms),
+            lines,
+            bytes,
+            processing: Duration::from_millis(processing_ms),
+        }
+    }
+
+    #[test]
+    fn test_fixed() {
+        let mut sizer = ChunkSizer::new(&ChunkSize::Fixed(50));
+
+        assert_eq!(sizer.update(measured(100, 50, 500, 1)), 50);
+    }
+
+    #[test]
+    fn test_adaptive() {
+        let adaptive = AdaptiveChunkSize::new(100, 5000);
+        let mut sizer = ChunkSizer::new(&ChunkSize::Adaptive(adaptive.clone()));
+        assert_eq!(sizer.size(), 1000);
+
+        // contended lock
+        assert_eq!(sizer.update(measured(10, 1000, 100_000, 20)), 2000);
+        assert_eq!(sizer.update(measured(10, 2000, 200_000, 40)), 4000);
+        assert_eq!(sizer.update(measured(10, 4000, 400_000, 80)), 5000);
+
+        // no contention
+        assert_eq!(sizer.update(measured(0, 5000, 500_000, 90)), 3750);
+
+        // slow lines are limited by the target time per chunk
+        assert_eq!(sizer.update(measured(10, 3750, 375_000, 3750)), 100);
+
+        let stats = adaptive.stats();
+        assert_eq!(
+            (stats.chunks, stats.smallest, stats.largest, stats.last),
+            (5, 1000, 5000, 3750)
+        );
+        assert_eq!(stats.lines, 15750);
+    }
+
+    #[test]
+    fn test_adaptive_long_lines() {
+        let mut sizer = ChunkSizer::new(&ChunkSize::Adaptive(AdaptiveChunkSize::new(1, 1000)));
+
+        assert_eq!(sizer.update(measured(10, 1000, 1000 << 20, 20)), 16);
+    }
+}
@@ -1,44 +1,108 @@
-use std::{borrow::Cow, fmt};
+use std::{cmp::Reverse, collections::BTreeMap, fmt, ops::Range, sync::Arc};
 
 use regex::Regex;
 
 use crate::{
-    pattern::{Pattern, PatternElement},
+    candidates::LengthIndex,
+    input::Line,
+    pattern::Pattern,
     scoring,
+    symbols::{Symbol, Symbols},
+    tokenizer::Tokenizer,
 };
 
+/// Number of distinct values of a dimension which are counted separately
+pub const MAX_DIMENSION_VALUES: usize = 100;
+
 #[derive(Clone, Copy)]
 pub struct ClustererOptions {
     pub max_dist: f64,
     pub min_members: u32,
 }
 
-pub struct Clusterer {
+/// Groups lines into clusters of similar lines. Lines are split into tokens
+/// with `T`, which by default splits them on a regex.
+pub struct Clusterer<T = Regex> {
     clusters: Vec<Cluster<'static>>,
+    /// Symbols of each of `clusters`, which lines are compared with
+    keys: Vec<ClusterKeys>,
+    /// Lengths of the representatives of `clusters`
+    index: LengthIndex,
+    symbols: Arc<Symbols>,
     options: ClustererOptions,
     pattern_backing_storage: Pattern<'static>,
-    split_regex: Regex,
+    key_backing_storage: Vec<Symbol>,
+    field_backing_storage: Vec<(usize, Range<usize>)>,
+    tokenizer: T,
+}
+
+/// Symbols of the items of the representative and pattern of a cluster
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ClusterKeys {
+    pub(crate) representative: Vec<Symbol>,
+    pub(crate) pattern: Vec<Symbol>,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct Cluster<'a> {
     pub representative: Pattern<'a>,
     pub count: u32,
     pub pattern: Pattern<'a>,
+    /// Breakdown of the lines of this cluster by each field the tokenizer
+    /// reported outside of their tokens (see `Tokenizer::tokenize_with_fields`)
+    pub dimensions: BTreeMap<String, Dimension>,
 }
 
-impl<'a> fmt::Display for Cluster<'a> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{} ", self.count)?;
+/// Number of lines with each value of a field
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Dimension {
+    /// Lines with each value, for up to `MAX_DIMENSION_VALUES` distinct values
+    pub values: BTreeMap<String, u32>,
+    /// Lines with a value which didn't fit in `values`
+    pub other: u32,
+}
 
-        for element in self.pattern.iter() {
-            match element {
-                PatternElement::Text(t) => write!(f, "{} ", t)?,
-                PatternElement::Placeholder => write!(f, "--- ")?,
-            }
+impl Dimension {
+    pub fn add(&mut self, value: &str, count: u32) {
+        if let Some(c) = self.values.get_mut(value) {
+            *c += count;
+        } else if self.values.len() < MAX_DIMENSION_VALUES {
+            self.values.insert(value.to_string(), count);
+        } else {
+            self.other += count;
         }
+    }
+
+    pub fn merge(&mut self, other: &Dimension) {
+        for (value, count) in &other.values {
+            self.add(value, *count);
+        }
+        self.other += other.other;
+    }
 
-        Ok(())
+    /// Values from the most to the least common
+    pub fn by_count(&self) -> Vec<(&str, u32)> {
+        let mut values: Vec<_> = self.values.iter().map(|(v, c)| (v.as_str(), *c)).collect();
+        values.sort_by_key(|(_, c)| Reverse(*c));
+        values
+    }
+}
+
+impl<'a> Cluster<'a> {
+    /// Add the dimensions of `other` to those of this cluster
+    pub fn merge_dimensions(&mut self, other: &BTreeMap<String, Dimension>) {
+        for (name, dimension) in other {
+            self.dimensions
+                .entry(name.clone())
+                .or_default()
+                .merge(dimension);
+        }
+    }
+}
+
+impl<'a> fmt::Display for Cluster<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.count, self.pattern)
     }
 }
 
@@ -63,77 +127,308 @@ impl Default for ClustererOptions {
     }
 }
 
-impl Clusterer {
-    pub fn new(options: ClustererOptions, split_regex: Regex) -> Self {
+impl<T: Tokenizer> Clusterer<T> {
+    pub fn new(options: ClustererOptions, tokenizer: T) -> Self {
         Self {
             options,
-            split_regex,
+            tokenizer,
             clusters: Default::default(),
+            keys: Default::default(),
+            index: Default::default(),
+            symbols: Default::default(),
             pattern_backing_storage: Default::default(),
+            key_backing_storage: Default::default(),
+            field_backing_storage: Default::default(),
         }
     }
 
-    pub fn process_line(&mut self, line: &str) {
+    /// Intern tokens in `symbols`, which may be shared with other clusterers,
+    /// rather than in a table of this clusterer's own
+    pub fn with_symbols(mut self, symbols: Arc<Symbols>) -> Self {
+        self.symbols = symbols;
+
+        let mut clusters = std::mem::take(&mut self.clusters);
+        self.keys = clusters.iter_mut().map(|c| self.share_cluster(c)).collect();
+        self.clusters = clusters;
+        self
+    }
+
+    pub fn symbols(&self) -> &Arc<Symbols> {
+        &self.symbols
+    }
+
+    /// Rebuild a clusterer from previously saved clusters
+    pub(crate) fn from_parts(
+        options: ClustererOptions,
+        tokenizer: T,
+        mut clusters: Vec<Cluster<'static>>,
+    ) -> Self {
+        let mut clusterer = Self::new(options, tokenizer);
+        clusterer.keys = clusters
+            .iter_mut()
+            .map(|c| clusterer.share_cluster(c))
+            .collect();
+        clusterer.index = LengthIndex::new(clusters.iter().map(|c| c.representative.len()));
+        clusterer.clusters = clusters;
+
+        clusterer
+    }
+
+    /// Share the text of `cluster` with the symbol table, returning its
+    /// symbols
+    fn share_cluster(&self, cluster: &mut Cluster<'static>) -> ClusterKeys {
+        let mut keys = ClusterKeys::default();
+        cluster.representative = self
+            .symbols
+            .share_pattern(&cluster.representative, &mut keys.representative);
+        cluster.pattern = self
+            .symbols
+            .share_pattern(&cluster.pattern, &mut keys.pattern);
+        keys
+    }
+
+    pub fn options(&self) -> ClustererOptions {
+        self.options
+    }
+
+    pub fn tokenizer(&self) -> &T {
+        &self.tokenizer
+    }
+
+    /// Add `line` to the closest cluster, or to a new cluster if none are
+    /// close enough. Returns the id of that cluster, which is its index in
+    /// `all_clusters`.
+    pub fn process_line(&mut self, line: &str) -> usize {
         let mut pattern = std::mem::take(&mut self.pattern_backing_storage).clear_and_reinterpret();
-        for t in self.split_regex.split(line) {
-            pattern.push_text(t);
-        }
+        let mut fields = std::mem::take(&mut self.field_backing_storage);
+        fields.clear();
+        pattern.push_line_with_fields(line, &self.tokenizer, &mut |index, value| {
+            fields.push((index, value))
+        });
 
-        for cluster in &mut self.clusters {
-            let score = scoring::distance(&cluster.representative, &pattern, self.options.max_dist);
+        let id = self.process_pattern(pattern, line, &fields);
 
-            if score <= self.options.max_dist {
-                cluster.count += 1;
-                let mut old_pattern = std::mem::take(&mut cluster.pattern);
+        self.field_backing_storage = fields;
+        id
+    }
 
-                cluster.pattern = old_pattern.merge(pattern);
+    /// Same as `process_line`, for a line of raw bytes which may not be UTF-8,
+    /// split with `Tokenizer::tokenize_bytes`
+    pub fn process_bytes(&mut self, line: &[u8]) -> usize {
+        let mut pattern = std::mem::take(&mut self.pattern_backing_storage).clear_and_reinterpret();
+        pattern.push_bytes(line, &self.tokenizer);
 
-                self.pattern_backing_storage = old_pattern;
+        // fields are only reported for text
+        self.process_pattern(pattern, "", &[])
+    }
 
-                return;
-            }
+    /// Add a line returned by `LineDecoder::decode` with `process_line` or
+    /// `process_bytes`
+    pub fn process(&mut self, line: &Line) -> usize {
+        match line {
+            Line::Text(text) => self.process_line(text),
+            Line::Bytes(bytes) => self.process_bytes(bytes),
         }
+    }
 
-        let mut old_pattern = pattern;
+    /// Add `line`, which was split into `pattern` and `fields`, to the closest
+    /// cluster or to a new one
+    fn process_pattern(
+        &mut self,
+        pattern: Pattern<'_>,
+        line: &str,
+        fields: &[(usize, Range<usize>)],
+    ) -> usize {
+        let mut keys = std::mem::take(&mut self.key_backing_storage);
+        self.symbols.lookup_pattern(&pattern, &mut keys);
+
+        let id = match self.find_cluster(&keys, 0..self.clusters.len()) {
+            Some(id) => {
+                let cluster = &mut self.clusters[id];
+                self.pattern_backing_storage =
+                    add_line(cluster, &mut self.keys[id], pattern, &keys);
+                record_fields(&self.tokenizer, cluster, line, fields);
+                id
+            }
+            None => {
+                let id = self.push_cluster(&pattern, line, fields);
+                self.pattern_backing_storage = pattern.clear_and_reinterpret();
+                id
+            }
+        };
 
-        let pattern = Pattern::new(
-            old_pattern
-                .drain()
-                .map(|element| match element {
-                    PatternElement::Placeholder => PatternElement::Placeholder,
-                    PatternElement::Text(t) => PatternElement::Text(Cow::Owned(t.into_owned())),
-                })
-                .collect(),
-        );
-        self.pattern_backing_storage = old_pattern.clear_and_reinterpret();
+        self.key_backing_storage = keys;
+        id
+    }
+
+    /// Symbols of the items of `pattern`, the same way lines are looked up by
+    /// `process_line`
+    pub(crate) fn lookup(&self, pattern: &Pattern) -> Vec<Symbol> {
+        let mut keys = Vec::new();
+        self.symbols.lookup_pattern(pattern, &mut keys);
+        keys
+    }
 
-        self.clusters.push(Cluster {
-            representative: pattern.clone(),
+    /// Id of the first of the `candidates` clusters which a line whose items
+    /// have the symbols `keys` is close enough to
+    pub(crate) fn find_cluster(&self, keys: &[Symbol], candidates: Range<usize>) -> Option<usize> {
+        let max_dist = self.options.max_dist;
+
+        self.index.find(keys.len(), max_dist, candidates, |&id| {
+            scoring::distance_of(&self.keys[id].representative, keys, max_dist) <= max_dist
+        })
+    }
+
+    /// Start a new cluster with `line`, which was split into `pattern` and
+    /// `fields`. Returns the id of the cluster.
+    pub(crate) fn push_cluster(
+        &mut self,
+        pattern: &Pattern,
+        line: &str,
+        fields: &[(usize, Range<usize>)],
+    ) -> usize {
+        let mut keys = ClusterKeys::default();
+        let new_pattern = self
+            .symbols
+            .share_pattern(pattern, &mut keys.representative);
+        keys.pattern = keys.representative.clone();
+        self.keys.push(keys);
+
+        let mut cluster = Cluster {
+            representative: new_pattern.clone(),
             count: 1,
-            pattern,
-        });
+            pattern: new_pattern,
+            dimensions: Default::default(),
+        };
+        record_fields(&self.tokenizer, &mut cluster, line, fields);
+        self.index.insert(pattern.len(), self.clusters.len());
+        self.clusters.push(cluster);
+
+        self.clusters.len() - 1
+    }
+
+    /// Every cluster found so far and their symbols, along with the
+    /// tokenizer, so that lines can be added to several clusters at once with
+    /// `add_line`
+    pub(crate) fn parts_mut(&mut self) -> (&mut [Cluster<'static>], &mut [ClusterKeys], &T) {
+        (&mut self.clusters, &mut self.keys, &self.tokenizer)
+    }
+
+    /// Iterate over the clusters found so far which meet `min_members`. Unlike
+    /// `take_result` this leaves the clusters in place, so more lines may be
+    /// processed afterwards.
+    pub fn clusters(&self) -> impl Iterator<Item = &Cluster<'static>> {
+        let min_members = self.options.min_members;
+
+        self.clusters.iter().filter(move |c| c.count >= min_members)
+    }
+
+    /// Copy of the clusters found so far which meet `min_members`, largest
+    /// first. Clusters of equal size are kept in the order they were created.
+    pub fn snapshot(&self) -> Vec<Cluster<'static>> {
+        let mut clusters: Vec<_> = self.clusters().cloned().collect();
+        clusters.sort_by_key(|c| Reverse(c.count));
+        clusters
+    }
+
+    /// Every cluster found so far, regardless of `min_members`, in the order
+    /// they were created
+    pub fn all_clusters(&self) -> &[Cluster<'static>] {
+        &self.clusters
     }
 
     pub fn take_result(&mut self) -> impl Iterator<Item = Cluster<'static>> {
         let clusters = std::mem::take(&mut self.clusters);
+        self.keys = Default::default();
+        self.index = Default::default();
 
         let min_members = self.options.min_members;
 
         clusters.into_iter().filter(move |c| c.count >= min_members)
     }
+
+    /// Copy of every cluster found so far along with its symbols, regardless
+    /// of `min_members`, in the order they were created
+    pub(crate) fn keyed_clusters(&self) -> Vec<(Cluster<'static>, ClusterKeys)> {
+        self.clusters
+            .iter()
+            .cloned()
+            .zip(self.keys.iter().cloned())
+            .collect()
+    }
+
+    /// Every cluster found along with its symbols, regardless of
+    /// `min_members`, in the order they were created
+    pub(crate) fn into_keyed_clusters(self) -> Vec<(Cluster<'static>, ClusterKeys)> {
+        self.clusters.into_iter().zip(self.keys).collect()
+    }
+}
+
+/// Add a line, which was split into `pattern` whose items have the symbols
+/// `keys`, to `cluster`, whose symbols are `cluster_keys`. The fields of the
+/// line are counted separately with `record_fields`. Returns the previous
+/// pattern of the cluster, whose storage can be reused.
+pub(crate) fn add_line(
+    cluster: &mut Cluster<'static>,
+    cluster_keys: &mut ClusterKeys,
+    pattern: Pattern<'_>,
+    keys: &[Symbol],
+) -> Pattern<'static> {
+    cluster.count += 1;
+    let mut old_pattern = std::mem::take(&mut cluster.pattern);
+
+    // the text of the merged pattern comes from the old one, so it is still
+    // shared with the symbol table
+    let (new_pattern, new_keys) = old_pattern.merge_keyed(&cluster_keys.pattern, pattern, keys);
+    cluster.pattern = new_pattern;
+    cluster_keys.pattern = new_keys;
+
+    old_pattern
+}
+
+/// Count the `fields` of `line`, as reported by
+/// `Tokenizer::tokenize_with_fields`, towards the dimensions of `cluster`
+pub(crate) fn record_fields(
+    tokenizer: &impl Tokenizer,
+    cluster: &mut Cluster,
+    line: &str,
+    fields: &[(usize, Range<usize>)],
+) {
+    for (index, value) in fields {
+        let name = tokenizer.field_name(*index);
+        let value = &line[value.clone()];
+
+        match cluster.dimensions.get_mut(name) {
+            Some(dimension) => dimension.add(value, 1),
+            None => {
+                let mut dimension = Dimension::default();
+                dimension.add(value, 1);
+                cluster.dimensions.insert(name.to_string(), dimension);
+            }
+        }
+    }
 }
 
 #[cfg(test)]
 mod test {
+    use std::sync::Arc;
+
     use regex::Regex;
 
     use crate::{
         clusterer::ClustererOptions,
         pattern::{Pattern, PatternElement},
+        symbols::Symbols,
     };
 
     use super::{Cluster, Clusterer};
 
+    /// Pattern of tokens which were separated by single spaces
+    fn spaced(items: Vec<PatternElement<'static>>) -> Pattern<'static> {
+        let separators = items.iter().skip(1).map(|_| " ".into()).collect();
+        Pattern::with_separators(items.into_iter().collect(), separators)
+    }
+
     impl Clusterer {
         fn find(mut self, input_lines: &[&str]) -> Vec<Cluster<'static>> {
             for line in input_lines {
@@ -158,19 +453,16 @@ mod test {
             clusters,
             vec![
                 Cluster {
-                    representative: Pattern::new(vec_into!["hello", "1", "y", "3"]),
+                    representative: spaced(vec_into!["hello", "1", "y", "3"]),
                     count: 2,
-                    pattern: Pattern::new(vec_into![
-                        "hello",
-                        "1",
-                        PatternElement::Placeholder,
-                        "3"
-                    ])
+                    pattern: spaced(vec_into!["hello", "1", PatternElement::Placeholder, "3"]),
+                    dimensions: Default::default(),
                 },
                 Cluster {
-                    representative: Pattern::new(vec_into!["abc", "m", "n", "q"]),
+                    representative: spaced(vec_into!["abc", "m", "n", "q"]),
                     count: 1,
-                    pattern: Pattern::new(vec_into!["abc", "m", "n", "q"])
+                    pattern: spaced(vec_into!["abc", "m", "n", "q"]),
+                    dimensions: Default::default(),
                 },
             ]
         );
@@ -190,13 +482,40 @@ mod test {
         assert_eq!(
             clusters,
             vec![Cluster {
-                representative: Pattern::new(vec_into!["hello", "1", "y", "3"]),
+                representative: spaced(vec_into!["hello", "1", "y", "3"]),
                 count: 2,
-                pattern: Pattern::new(vec_into!["hello", "1", PatternElement::Placeholder, "3"])
+                pattern: spaced(vec_into!["hello", "1", PatternElement::Placeholder, "3"]),
+                dimensions: Default::default(),
             }]
         );
     }
 
+    #[test]
+    fn test_snapshot_does_not_consume() {
+        let mut clusterer = Clusterer::new(
+            ClustererOptions {
+                max_dist: 0.5,
+                min_members: 2,
+            },
+            Regex::new("\\s+").unwrap(),
+        );
+
+        for line in &["abc m n q", "hello 1 y 3", "hello 1 x 3", "abc m n q"] {
+            clusterer.process_line(line);
+        }
+
+        let counts = |clusters: &[Cluster]| clusters.iter().map(|c| c.count).collect::<Vec<_>>();
+
+        assert_eq!(counts(&clusterer.snapshot()), vec![2, 2]);
+
+        clusterer.process_line("hello 1 z 3");
+        assert_eq!(counts(&clusterer.snapshot()), vec![3, 2]);
+        assert_eq!(
+            counts(&clusterer.take_result().collect::<Vec<_>>()),
+            vec![2, 3]
+        );
+    }
+
     #[test]
     fn test_small_max_dist() {
         let clusters = Clusterer::new(
@@ -212,21 +531,68 @@ mod test {
             clusters,
             vec![
                 Cluster {
-                    representative: Pattern::new(vec_into!["hello", "1", "y", "3"]),
+                    representative: spaced(vec_into!["hello", "1", "y", "3"]),
                     count: 1,
-                    pattern: Pattern::new(vec_into!["hello", "1", "y", "3"])
+                    pattern: spaced(vec_into!["hello", "1", "y", "3"]),
+                    dimensions: Default::default(),
                 },
                 Cluster {
-                    representative: Pattern::new(vec_into!["hello", "1", "x", "3"]),
+                    representative: spaced(vec_into!["hello", "1", "x", "3"]),
                     count: 1,
-                    pattern: Pattern::new(vec_into!["hello", "1", "x", "3"])
+                    pattern: spaced(vec_into!["hello", "1", "x", "3"]),
+                    dimensions: Default::default(),
                 },
                 Cluster {
-                    representative: Pattern::new(vec_into!["abc", "m", "n", "q"]),
+                    representative: spaced(vec_into!["abc", "m", "n", "q"]),
                     count: 1,
-                    pattern: Pattern::new(vec_into!["abc", "m", "n", "q"])
+                    pattern: spaced(vec_into!["abc", "m", "n", "q"]),
+                    dimensions: Default::default(),
                 },
             ]
         );
     }
+
+    #[test]
+    fn test_shared_symbols() {
+        let options = ClustererOptions {
+            max_dist: 0.5,
+            ..Default::default()
+        };
+        let lines = ["a b 1 x", "a b 2 x", "c d e f", "a b 3 y"];
+        let symbols = Arc::new(Symbols::new());
+
+        let own = Clusterer::new(options, Regex::new("\\s+").unwrap()).find(&lines);
+        let shared = Clusterer::new(options, Regex::new("\\s+").unwrap())
+            .with_symbols(symbols.clone())
+            .find(&lines);
+
+        assert_eq!(own, shared);
+        // lines which join a cluster are only looked up, so "2", "3" and "y"
+        // are never interned, while the separator " " is
+        assert_eq!(symbols.len(), 9);
+    }
+
+    #[test]
+    fn test_process_bytes() {
+        let mut clusterer = Clusterer::new(
+            ClustererOptions {
+                max_dist: 0.01,
+                ..Default::default()
+            },
+            Regex::new("\\s+").unwrap(),
+        );
+
+        // raw bytes equal to a text line join its cluster, while others which
+        // would be shown the same don't
+        let ids = [
+            clusterer.process_line("a \u{e9}\n"),
+            clusterer.process_bytes(b"a \xc3\xa9\n"),
+            clusterer.process_bytes(b"a \xe9\n"),
+        ];
+        assert_eq!(ids, [0, 0, 1]);
+        assert_eq!(
+            clusterer.all_clusters()[1].pattern.to_string(),
+            "a \u{fffd}"
+        );
+    }
 }
@@ -0,0 +1,145 @@
+//! Named profiles of command line settings, read from TOML config files such
+//! as:
+//!
+//! ```toml
+//! [profile.hdfs]
+//! message-regex = '^(?P<date>\S+) (?P<time>\S+) (?P<pid>\d+) (?P<level>\w+) (?P<msg>.*)$'
+//! max-distance = 0.4
+//! min-members = 5
+//! format = "json"
+//! ```
+//!
+//! A profile only holds settings which stand in for command line flags, so
+//! there is no setting for masks, which would replace the variable parts of
+//! lines, such as IP addresses, with a name like `<ip>` before clustering.
+//! logmine has no flag for masks yet, and a `masks` setting belongs with one.
+
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+/// Name of the project-local config file, looked for in the current directory
+pub const PROJECT_CONFIG_FILE: &str = ".logmine.toml";
+
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub profile: BTreeMap<String, Profile>,
+}
+
+/// Settings of a profile. Each is named after the command line flag it stands
+/// in for, and is only used when that flag isn't given.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Profile {
+    pub split_pattern: Option<String>,
+    pub tokenizer: Option<String>,
+    pub token_pattern: Option<String>,
+    pub delimiters: Option<String>,
+    pub keep_delimiters: Option<String>,
+    pub message_regex: Option<String>,
+    pub max_distance: Option<f64>,
+    pub min_members: Option<u32>,
+    pub top: Option<usize>,
+    pub regex: Option<bool>,
+    pub format: Option<String>,
+    pub color: Option<String>,
+}
+
+impl Config {
+    pub fn parse(text: &str) -> io::Result<Self> {
+        toml::from_str(text).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+    }
+
+    pub fn read(path: &Path) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string(path)?).map_err(|e| {
+            io::Error::new(ErrorKind::InvalidData, format!("{}: {}", path.display(), e))
+        })
+    }
+
+    /// Read and merge each of `default_paths` which exists, so that profiles
+    /// in the project config replace those of the same name in the user
+    /// config
+    pub fn read_default() -> io::Result<Self> {
+        let mut config = Self::default();
+        for path in default_paths() {
+            match Self::read(&path) {
+                Ok(c) => config.merge(c),
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Add the profiles of `other`, replacing any with the same name
+    pub fn merge(&mut self, other: Config) {
+        self.profile.extend(other.profile);
+    }
+}
+
+/// The user config, `$XDG_CONFIG_HOME/logmine/config.toml` (or
+/// `~/.config/logmine/config.toml`), followed by `PROJECT_CONFIG_FILE`
+pub fn default_paths() -> Vec<PathBuf> {
+    let config_home = env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")));
+
+    config_home
+        .map(|dir| dir.join("logmine").join("config.toml"))
+        .into_iter()
+        .chain(Some(PathBuf::from(PROJECT_CONFIG_FILE)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, Profile};
+
+    #[test]
+    fn test_parse() {
+        let config = Config::parse(
+            r#"
+            [profile.hdfs]
+            split-pattern = '\s+'
+            max-distance = 0.4
+            format = "json"
+
+            [profile.empty]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.profile["hdfs"],
+            Profile {
+                split_pattern: Some("\\s+".to_string()),
+                max_distance: Some(0.4),
+                format: Some("json".to_string()),
+                ..Default::default()
+            }
+        );
+        assert_eq!(config.profile["empty"], Profile::default());
+
+        assert!(Config::parse("[profile.x]\nmax-dist = 1").is_err());
+        assert!(Config::parse("[profile.x]\nmasks = []").is_err());
+    }
+
+    #[test]
+    fn test_merge_replaces_profiles() {
+        let mut config = Config::parse("[profile.a]\ntop = 1\n[profile.b]\ntop = 2").unwrap();
+        config.merge(Config::parse("[profile.a]\nregex = true").unwrap());
+
+        assert_eq!(config.profile["a"].top, None);
+        assert_eq!(config.profile["a"].regex, Some(true));
+        assert_eq!(config.profile["b"].top, Some(2));
+    }
+}
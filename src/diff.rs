@@ -0,0 +1,207 @@
+use std::{cmp::Reverse, fmt};
+
+use crate::{
+    clusterer::{Cluster, ClustererOptions},
+    pattern::Pattern,
+    scoring,
+};
+
+/// Differences between the clusters found in two inputs, `a` and `b`
+#[derive(Debug, Default)]
+pub struct PatternDiff {
+    /// Clusters of `a` which have no counterpart in `b`, largest first
+    pub only_a: Vec<Cluster<'static>>,
+    /// Clusters of `b` which have no counterpart in `a`, largest first
+    pub only_b: Vec<Cluster<'static>>,
+    /// Clusters found in both inputs whose share of their input's lines
+    /// changed by at least the requested ratio, largest change first
+    pub changed: Vec<SharedPattern>,
+    /// Number of lines in `a`
+    pub total_a: u64,
+    /// Number of lines in `b`
+    pub total_b: u64,
+}
+
+/// A pattern found in both inputs of a diff
+#[derive(Debug, PartialEq)]
+pub struct SharedPattern {
+    /// The patterns of both sides merged together
+    pub pattern: Pattern<'static>,
+    pub count_a: u32,
+    pub count_b: u32,
+    /// Fraction of the lines of `a` which belong to this pattern
+    pub frequency_a: f64,
+    /// Fraction of the lines of `b` which belong to this pattern
+    pub frequency_b: f64,
+}
+
+impl SharedPattern {
+    /// How many times more (or less) frequent the pattern is in `b` compared
+    /// to `a`. Always at least 1.
+    pub fn change_ratio(&self) -> f64 {
+        let (low, high) = if self.frequency_a < self.frequency_b {
+            (self.frequency_a, self.frequency_b)
+        } else {
+            (self.frequency_b, self.frequency_a)
+        };
+
+        high / low
+    }
+}
+
+impl fmt::Display for SharedPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} -> {} ({:.2}% -> {:.2}%) {}",
+            self.count_a,
+            self.count_b,
+            self.frequency_a * 100.0,
+            self.frequency_b * 100.0,
+            self.pattern
+        )
+    }
+}
+
+/// Pair up the clusters of two inputs. `a` and `b` should contain every
+/// cluster found, regardless of size, so that the frequency of each pattern
+/// can be calculated; clusters smaller than `options.min_members` are left out
+/// of the result afterwards.
+///
+/// Clusters are paired by the distance between their representatives, in the
+/// same way that lines are assigned to clusters. Larger clusters of `a` are
+/// paired first, each with the closest cluster of `b` which is within
+/// `options.max_dist` and not yet paired. Shared patterns are only reported if
+/// one side is at least `min_change_ratio` times as frequent as the other.
+pub fn diff(
+    mut a: Vec<Cluster<'static>>,
+    mut b: Vec<Cluster<'static>>,
+    options: ClustererOptions,
+    min_change_ratio: f64,
+) -> PatternDiff {
+    let total_a: u64 = a.iter().map(|c| c.count as u64).sum();
+    let total_b: u64 = b.iter().map(|c| c.count as u64).sum();
+
+    a.sort_by_key(|c| Reverse(c.count));
+
+    let mut result = PatternDiff {
+        total_a,
+        total_b,
+        ..Default::default()
+    };
+
+    for mut cluster_a in a {
+        let mut best: Option<(usize, f64)> = None;
+        for (i, cluster_b) in b.iter().enumerate() {
+            // a max distance of 0 disables the early return in `distance`, so
+            // that the exact distance can be compared between clusters
+            let dist = scoring::distance(&cluster_a.representative, &cluster_b.representative, 0.0);
+
+            let is_best = match best {
+                Some((_, best_dist)) => dist < best_dist,
+                None => true,
+            };
+
+            if dist <= options.max_dist && is_best {
+                best = Some((i, dist));
+            }
+        }
+
+        let cluster_b = match best {
+            Some((i, _)) => b.remove(i),
+            None => {
+                if cluster_a.count >= options.min_members {
+                    result.only_a.push(cluster_a);
+                }
+                continue;
+            }
+        };
+
+        if cluster_a.count.max(cluster_b.count) < options.min_members {
+            continue;
+        }
+
+        let shared = SharedPattern {
+            frequency_a: cluster_a.count as f64 / total_a as f64,
+            frequency_b: cluster_b.count as f64 / total_b as f64,
+            count_a: cluster_a.count,
+            count_b: cluster_b.count,
+            pattern: cluster_a.pattern.merge(cluster_b.pattern),
+        };
+
+        if shared.change_ratio() >= min_change_ratio {
+            result.changed.push(shared);
+        }
+    }
+
+    result.only_b = b
+        .into_iter()
+        .filter(|c| c.count >= options.min_members)
+        .collect();
+    result.only_b.sort_by_key(|c| Reverse(c.count));
+
+    result
+        .changed
+        .sort_by(|s1, s2| s2.change_ratio().total_cmp(&s1.change_ratio()));
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use regex::Regex;
+
+    use crate::clusterer::{Cluster, Clusterer, ClustererOptions};
+
+    use super::diff;
+
+    fn clusters(options: ClustererOptions, lines: &[&str]) -> Vec<Cluster<'static>> {
+        let mut clusterer = Clusterer::new(options, Regex::new("\\s+").unwrap());
+        for line in lines {
+            clusterer.process_line(line);
+        }
+        clusterer.take_result().collect()
+    }
+
+    #[test]
+    fn test_diff() {
+        let options = ClustererOptions::default().with_max_dist(0.5);
+
+        let a = clusters(
+            options,
+            &[
+                "connected to db 1",
+                "connected to db 2",
+                "request took 5 ms",
+                "request took 6 ms",
+                "cache miss for a",
+            ],
+        );
+        let b = clusters(
+            options,
+            &[
+                "connected to db 3",
+                "request took 7 ms",
+                "request took 8 ms",
+                "request took 9 ms",
+                "request took 1 ms",
+                "request took 2 ms",
+                "request took 3 ms",
+                "panic in thread main",
+            ],
+        );
+
+        let result = diff(a, b, options, 2.0);
+
+        let only_a: Vec<_> = result.only_a.iter().map(|c| c.to_string()).collect();
+        let only_b: Vec<_> = result.only_b.iter().map(|c| c.to_string()).collect();
+        let changed: Vec<_> = result.changed.iter().map(|s| s.to_string()).collect();
+
+        assert_eq!(only_a, vec!["1 cache miss for a"]);
+        assert_eq!(only_b, vec!["1 panic in thread main"]);
+        assert_eq!(
+            changed,
+            vec!["2 -> 1 (40.00% -> 12.50%) connected to db ---"]
+        );
+    }
+}
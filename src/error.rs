@@ -0,0 +1,47 @@
+use std::{fmt, io};
+
+/// Errors from reading input and setting up a run
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// A line wasn't valid UTF-8, and the `InvalidUtf8` policy was `Fail`
+    InvalidUtf8,
+    Regex(regex::Error),
+    /// Invalid combination of settings, such as an unknown profile
+    Config(String),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => e.fmt(f),
+            Error::InvalidUtf8 => write!(f, "input contains a line which is not valid UTF-8"),
+            Error::Regex(e) => e.fmt(f),
+            Error::Config(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Regex(e) => Some(e),
+            Error::InvalidUtf8 | Error::Config(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<regex::Error> for Error {
+    fn from(e: regex::Error) -> Self {
+        Error::Regex(e)
+    }
+}
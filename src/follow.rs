@@ -0,0 +1,261 @@
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+/// How many of the bytes before the read position are kept to tell whether a
+/// file was rewritten (see `Follower::tail`)
+const TAIL_LEN: usize = 64;
+
+/// Reads lines appended to a file, in the manner of `tail -F`. The file is
+/// re-opened from the start when it is replaced (log rotation) and re-read
+/// from the start when it shrinks or the bytes before the read position change
+/// (truncation, even if the file is written past that position again before
+/// the next read).
+pub struct Follower {
+    path: PathBuf,
+    reader: BufReader<File>,
+    position: u64,
+    identity: Option<FileIdentity>,
+    /// Text of a line which has been partially written to the file. It is
+    /// only handed out once the writer finishes it with a newline.
+    partial: Vec<u8>,
+    /// The last `TAIL_LEN` bytes before `position`. A file which is truncated
+    /// and rewritten with the same bytes there isn't noticed.
+    tail: Vec<u8>,
+    /// Whether the end of the file was reached, so that `tail` is checked
+    /// before reading any more
+    idle: bool,
+}
+
+#[derive(PartialEq, Clone, Copy)]
+struct FileIdentity {
+    dev: u64,
+    ino: u64,
+}
+
+impl Follower {
+    /// Start following the file at `path`. Only lines written after this call
+    /// will be returned.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_owned();
+        let mut file = File::open(&path)?;
+        let identity = identity(&file)?;
+        let end = file.seek(SeekFrom::End(0))?;
+
+        let mut tail = vec![0; end.min(TAIL_LEN as u64) as usize];
+        file.seek(SeekFrom::Start(end - tail.len() as u64))?;
+        file.read_exact(&mut tail)?;
+
+        Ok(Self {
+            path,
+            reader: BufReader::new(file),
+            position: end,
+            identity,
+            partial: Vec::new(),
+            tail,
+            idle: false,
+        })
+    }
+
+    /// Read the next complete line into `line`, replacing its contents.
+    /// Returns `false` when no complete line is available yet; the caller
+    /// should wait a while before trying again.
+    pub fn read_line(&mut self, line: &mut Vec<u8>) -> io::Result<bool> {
+        if self.idle {
+            self.idle = false;
+            if !self.tail_unchanged()? {
+                self.restart()?;
+            }
+        }
+
+        loop {
+            let size = self.reader.read_until(b'\n', &mut self.partial)?;
+            self.position += size as u64;
+
+            if size == 0 {
+                if self.check_rotation()? {
+                    continue;
+                }
+
+                self.idle = true;
+                return Ok(false);
+            }
+
+            self.tail
+                .extend_from_slice(&self.partial[self.partial.len() - size..]);
+            let excess = self.tail.len().saturating_sub(TAIL_LEN);
+            self.tail.drain(..excess);
+
+            if self.partial.ends_with(b"\n") {
+                line.clear();
+                std::mem::swap(line, &mut self.partial);
+                return Ok(true);
+            }
+        }
+    }
+
+    /// Compare the open file with the one currently at `path`. Returns `true`
+    /// if the reader was reset and there may be new data to read.
+    fn check_rotation(&mut self) -> io::Result<bool> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            // the old file has been moved away but the new one hasn't been
+            // created yet
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        };
+
+        let identity = identity(&file)?;
+        if identity != self.identity {
+            self.reader = BufReader::new(file);
+            self.identity = identity;
+            self.position = 0;
+            self.partial.clear();
+            self.tail.clear();
+            return Ok(true);
+        }
+
+        if file.metadata()?.len() < self.position {
+            self.restart()?;
+            return Ok(true);
+        }
+
+        Ok(false)
+    }
+
+    /// Whether the bytes before `position` are still `tail`, leaving the
+    /// reader at `position` if they are
+    fn tail_unchanged(&mut self) -> io::Result<bool> {
+        let mut bytes = vec![0; self.tail.len()];
+        self.reader
+            .seek(SeekFrom::Start(self.position - bytes.len() as u64))?;
+
+        match self.reader.read_exact(&mut bytes) {
+            Ok(()) => Ok(bytes == self.tail),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Read the file again from the start
+    fn restart(&mut self) -> io::Result<()> {
+        self.position = self.reader.seek(SeekFrom::Start(0))?;
+        self.partial.clear();
+        self.tail.clear();
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn identity(file: &File) -> io::Result<Option<FileIdentity>> {
+    use std::os::unix::fs::MetadataExt;
+
+    let metadata = file.metadata()?;
+
+    Ok(Some(FileIdentity {
+        dev: metadata.dev(),
+        ino: metadata.ino(),
+    }))
+}
+
+/// Rotation can only be detected through truncation on platforms without
+/// inode numbers.
+#[cfg(not(unix))]
+fn identity(_file: &File) -> io::Result<Option<FileIdentity>> {
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::{self, File, OpenOptions},
+        io::Write,
+        path::PathBuf,
+    };
+
+    use super::Follower;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("logmine-{}-{}", name, std::process::id()))
+    }
+
+    fn append(path: &PathBuf, text: &str) {
+        let mut f = OpenOptions::new().append(true).open(path).unwrap();
+        f.write_all(text.as_bytes()).unwrap();
+    }
+
+    fn read_all(follower: &mut Follower) -> Vec<String> {
+        let mut lines = Vec::new();
+        let mut line = Vec::new();
+        while follower.read_line(&mut line).unwrap() {
+            lines.push(String::from_utf8(line.clone()).unwrap());
+        }
+        lines
+    }
+
+    #[test]
+    fn test_follow_partial_lines_and_truncation() {
+        let path = temp_path("truncate");
+        fs::write(&path, "old line\n").unwrap();
+
+        let mut follower = Follower::open(&path).unwrap();
+        assert!(read_all(&mut follower).is_empty());
+
+        append(&path, "a 1\nb ");
+        assert_eq!(read_all(&mut follower), vec!["a 1\n"]);
+
+        append(&path, "2\n");
+        assert_eq!(read_all(&mut follower), vec!["b 2\n"]);
+
+        File::create(&path).unwrap();
+        append(&path, "c\n");
+        assert_eq!(read_all(&mut follower), vec!["c\n"]);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_follow_truncation_past_position() {
+        let path = temp_path("rewrite");
+        fs::write(&path, "old line\n").unwrap();
+
+        let mut follower = Follower::open(&path).unwrap();
+        append(&path, "a 1\n");
+        assert_eq!(read_all(&mut follower), vec!["a 1\n"]);
+
+        // truncated and written past the old position between two reads
+        fs::write(&path, "b 2 is a longer line\nc 3\n").unwrap();
+        assert_eq!(
+            read_all(&mut follower),
+            vec!["b 2 is a longer line\n", "c 3\n"]
+        );
+
+        append(&path, "d 4\n");
+        assert_eq!(read_all(&mut follower), vec!["d 4\n"]);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_follow_rotation() {
+        let path = temp_path("rotate");
+        let rotated = temp_path("rotate.1");
+        fs::write(&path, "").unwrap();
+
+        let mut follower = Follower::open(&path).unwrap();
+        append(&path, "before rotation\n");
+        fs::rename(&path, &rotated).unwrap();
+        fs::write(&path, "after rotation with a longer line\n").unwrap();
+
+        assert_eq!(
+            read_all(&mut follower),
+            vec!["before rotation\n", "after rotation with a longer line\n"]
+        );
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&rotated).unwrap();
+    }
+}
@@ -0,0 +1,197 @@
+//! Reading lines of input which may not be valid UTF-8
+//!
+//! Lines are read as bytes and then decoded, one at a time, with
+//! `LineDecoder::decode`. In byte mode (see `LineDecoder::bytes`) lines aren't
+//! decoded at all, and are clustered on their raw bytes instead (see
+//! `Clusterer::process_bytes`).
+
+use std::{
+    borrow::Cow,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use crate::error::{Error, Result};
+
+/// What to do with a line which isn't valid UTF-8
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InvalidUtf8 {
+    /// Leave the line out, as if it wasn't in the input
+    Skip,
+    /// Replace each invalid sequence with U+FFFD
+    Lossy,
+    /// Stop with `Error::InvalidUtf8`
+    Fail,
+}
+
+impl FromStr for InvalidUtf8 {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(Self::Skip),
+            "lossy" => Ok(Self::Lossy),
+            "fail" => Ok(Self::Fail),
+            _ => Err(format!(
+                "unknown invalid UTF-8 policy {:?}, expected skip, lossy or fail",
+                s
+            )),
+        }
+    }
+}
+
+/// A line returned by `LineDecoder::decode`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Line<'l> {
+    Text(Cow<'l, str>),
+    /// The raw bytes of a line read in byte mode
+    Bytes(&'l [u8]),
+}
+
+impl<'l> Line<'l> {
+    /// The bytes to write out for this line, which are the bytes it was read
+    /// from unless it was decoded lossily
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            Line::Text(text) => text.as_bytes(),
+            Line::Bytes(bytes) => bytes,
+        }
+    }
+
+    /// This line, with raw bytes decoded as UTF-8 and any invalid sequences
+    /// replaced by U+FFFD
+    pub fn to_str_lossy(&self) -> Cow<'_, str> {
+        match self {
+            Line::Text(text) => Cow::Borrowed(text),
+            Line::Bytes(bytes) => String::from_utf8_lossy(bytes),
+        }
+    }
+}
+
+/// Turns lines of bytes into strings according to an `InvalidUtf8` policy,
+/// counting the lines which weren't valid UTF-8. Clones share the same count,
+/// so one decoder can be cloned into each thread reading the same input.
+#[derive(Debug, Clone)]
+pub struct LineDecoder {
+    policy: InvalidUtf8,
+    invalid_lines: Arc<AtomicU64>,
+    bytes: bool,
+}
+
+impl LineDecoder {
+    pub fn new(policy: InvalidUtf8) -> Self {
+        Self {
+            policy,
+            invalid_lines: Default::default(),
+            bytes: false,
+        }
+    }
+
+    /// Keep every line as raw bytes, whether or not it is valid UTF-8
+    pub fn bytes() -> Self {
+        Self {
+            bytes: true,
+            ..Self::default()
+        }
+    }
+
+    pub fn policy(&self) -> InvalidUtf8 {
+        self.policy
+    }
+
+    /// Number of lines decoded so far which weren't valid UTF-8, including
+    /// those which were skipped
+    pub fn invalid_lines(&self) -> u64 {
+        self.invalid_lines.load(Ordering::Relaxed)
+    }
+
+    /// Decode a single line, borrowing `bytes` unless it is decoded lossily.
+    /// Returns `None` if it should be skipped.
+    pub fn decode<'l>(&self, bytes: &'l [u8]) -> Result<Option<Line<'l>>> {
+        if self.bytes {
+            return Ok(Some(Line::Bytes(bytes)));
+        }
+
+        if let Ok(line) = std::str::from_utf8(bytes) {
+            return Ok(Some(Line::Text(Cow::Borrowed(line))));
+        }
+
+        self.invalid_lines.fetch_add(1, Ordering::Relaxed);
+        match self.policy {
+            InvalidUtf8::Skip => Ok(None),
+            InvalidUtf8::Lossy => Ok(Some(Line::Text(String::from_utf8_lossy(bytes)))),
+            InvalidUtf8::Fail => Err(Error::InvalidUtf8),
+        }
+    }
+}
+
+/// Lines which aren't valid UTF-8 are an error
+impl Default for LineDecoder {
+    fn default() -> Self {
+        Self::new(InvalidUtf8::Fail)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use crate::error::Error;
+
+    use super::{InvalidUtf8, Line, LineDecoder};
+
+    const INPUT: &[u8] = b"a 1\nb \xff 2\nc 3";
+
+    fn decode_all(decoder: &LineDecoder) -> Result<Vec<String>, Error> {
+        let mut lines = Vec::new();
+        for line in INPUT.split_inclusive(|&b| b == b'\n') {
+            if let Some(line) = decoder.decode(line)? {
+                lines.push(line.to_str_lossy().into_owned());
+            }
+        }
+        Ok(lines)
+    }
+
+    #[test]
+    fn test_policies() {
+        let skip = LineDecoder::new(InvalidUtf8::Skip);
+        assert_eq!(decode_all(&skip).unwrap(), vec!["a 1\n", "c 3"]);
+        assert_eq!(skip.invalid_lines(), 1);
+
+        let lossy = LineDecoder::new(InvalidUtf8::Lossy);
+        assert_eq!(
+            decode_all(&lossy).unwrap(),
+            vec!["a 1\n", "b \u{fffd} 2\n", "c 3"]
+        );
+        assert_eq!(lossy.clone().invalid_lines(), 1);
+
+        let fail = LineDecoder::new(InvalidUtf8::Fail);
+        assert!(matches!(decode_all(&fail), Err(Error::InvalidUtf8)));
+    }
+
+    #[test]
+    fn test_decode_borrows() {
+        let lossy = LineDecoder::new(InvalidUtf8::Lossy);
+        assert!(matches!(
+            lossy.decode(b"a\n").unwrap(),
+            Some(Line::Text(Cow::Borrowed("a\n")))
+        ));
+        assert_eq!(
+            lossy.decode(b"\xff").unwrap().unwrap().as_bytes(),
+            "\u{fffd}".as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_bytes() {
+        let decoder = LineDecoder::bytes();
+        let line = decoder.decode(b"caf\xc3\xa9 \xff\n").unwrap().unwrap();
+
+        assert_eq!(line, Line::Bytes(b"caf\xc3\xa9 \xff\n"));
+        assert_eq!(line.to_str_lossy(), "caf\u{e9} \u{fffd}\n");
+        assert_eq!(decoder.invalid_lines(), 0);
+    }
+}
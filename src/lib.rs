@@ -1,18 +1,34 @@
-use std::io::BufRead;
+use std::io::{self, BufRead};
 
 use clusterer::{Cluster, Clusterer, ClustererOptions};
 use indicatif::ProgressBar;
-use regex::Regex;
+use input::{Line, LineDecoder};
+use tokenizer::Tokenizer;
+
+pub use error::{Error, Result};
 
 #[macro_use]
 #[cfg(test)]
 mod macros;
 
+pub mod annotate;
+mod candidates;
+pub mod chunk_size;
 pub mod clusterer;
+pub mod config;
+pub mod diff;
+mod error;
+pub mod follow;
+pub mod input;
+pub mod matcher;
 pub mod parallel_clusterer;
 pub mod pattern;
 mod pool;
+pub mod report;
 pub mod scoring;
+pub mod state;
+pub mod symbols;
+pub mod tokenizer;
 
 /// special-cased runner for when user passes --jobs=1. This avoids the
 /// threading & communication overhead of the parallel mode (~10%). With a non-1
@@ -20,27 +36,55 @@ pub mod scoring;
 /// parallelism.
 pub fn main_single_core(
     options: ClustererOptions,
-    mut file: impl BufRead,
+    file: impl BufRead,
+    decoder: &LineDecoder,
+    progress: ProgressBar,
+    tokenizer: impl Tokenizer,
+) -> Result<Vec<Cluster<'static>>> {
+    let mut clusterer = Clusterer::new(options, tokenizer);
+
+    process_reader(&mut clusterer, file, decoder, progress)?;
+
+    Ok(clusterer.take_result().collect())
+}
+
+/// Feed every line of `file` into an existing clusterer on the current thread
+pub fn process_reader<T: Tokenizer>(
+    clusterer: &mut Clusterer<T>,
+    file: impl BufRead,
+    decoder: &LineDecoder,
     progress: ProgressBar,
-    split_regex: Regex,
-) -> Vec<Cluster<'static>> {
-    let mut clusterer = Clusterer::new(options, split_regex);
+) -> Result<()> {
+    process_reader_annotated(clusterer, file, decoder, progress, |_, _| Ok(()))
+}
 
-    let mut line = String::new();
+/// Same as `process_reader`, but `on_line` is called with each line which
+/// isn't skipped and the id of the cluster it was added to
+pub fn process_reader_annotated<T: Tokenizer>(
+    clusterer: &mut Clusterer<T>,
+    mut file: impl BufRead,
+    decoder: &LineDecoder,
+    progress: ProgressBar,
+    mut on_line: impl FnMut(&Line, usize) -> io::Result<()>,
+) -> Result<()> {
+    let mut line = Vec::new();
 
-    'outer: loop {
+    loop {
         let mut size = 0;
         for _ in 0..100 {
             line.clear();
-            if file.read_line(&mut line).unwrap() == 0 {
-                break 'outer;
+            let read = file.read_until(b'\n', &mut line)?;
+            if read == 0 {
+                progress.inc(size as u64);
+                return Ok(());
             }
 
-            clusterer.process_line(&line);
-            size += line.len();
+            if let Some(line) = decoder.decode(&line)? {
+                let cluster_id = clusterer.process(&line);
+                on_line(&line, cluster_id)?;
+            }
+            size += read;
         }
         progress.inc(size as u64);
     }
-
-    clusterer.take_result().collect()
 }
@@ -1,10 +1,32 @@
-use std::{fs::File, io::BufReader, path::PathBuf};
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, Instant},
+};
 
+use console::Term;
 use indicatif::{ProgressBar, ProgressStyle};
-use logmine_rs::clusterer::ClustererOptions;
+use logmine_rs::{
+    annotate::{AnnotationFormat, Annotations, Annotator},
+    chunk_size::{AdaptiveChunkSize, ChunkSize, DEFAULT_CHUNK_SIZE},
+    clusterer::{Cluster, Clusterer, ClustererOptions},
+    config::{Config, Profile},
+    diff,
+    follow::Follower,
+    input::{InvalidUtf8, Line, LineDecoder},
+    matcher::{Matcher, NoveltyDetector},
+    report::{self, ColorChoice, Format, Report, ReportOptions},
+    tokenizer::{
+        BuiltinTokenizer, BytesRegex, Delimiters, KeepDelimiters, MessageRegex, QuoteAware,
+        RegexFind, Tokenizer,
+    },
+    Error,
+};
 use rayon::ThreadPoolBuilder;
 use regex::Regex;
-use structopt::StructOpt;
+use structopt::{clap::ArgMatches, StructOpt};
 
 #[derive(structopt::StructOpt)]
 /// Find patterns in log files
@@ -14,9 +36,45 @@ struct Options {
     jobs: Option<usize>,
 
     /// Number of lines read at a time by each thread when running in parallel
-    /// mode. Has zero effect when --jobs=1.
-    #[structopt(long, short = "c", default_value = "10000")]
-    parallel_read_chunk_size: usize,
+    /// mode. By default threads which take turns to read the input adapt the
+    /// number as they go, between --min-chunk-size and --max-chunk-size, and
+    /// other modes read 10000 lines at a time. Has zero effect when --jobs=1.
+    #[structopt(long, short = "c")]
+    parallel_read_chunk_size: Option<usize>,
+
+    /// Fewest lines read at a time when adapting the chunk size.
+    #[structopt(long, default_value = "100")]
+    min_chunk_size: usize,
+
+    /// Most lines read at a time when adapting the chunk size.
+    #[structopt(long, default_value = "100000")]
+    max_chunk_size: usize,
+
+    /// Print statistics about the run to stderr, such as the chunk sizes
+    /// chosen in parallel mode.
+    #[structopt(long, short)]
+    verbose: bool,
+
+    /// Memory map the input if it is a regular file, giving each thread its
+    /// own part of the file rather than having threads take turns reading it.
+    /// The file must not be truncated while it is read: the process is killed
+    /// with SIGBUS if a mapped page disappears, so don't use this on logs
+    /// which may be rotated or truncated. Has zero effect when --jobs=1.
+    #[structopt(long, conflicts_with_all = &["deterministic", "pipeline"])]
+    mmap: bool,
+
+    /// Find exactly the clusters that --jobs=1 would, whatever the number of
+    /// threads. Lines are still matched to clusters in parallel, but each
+    /// chunk of --parallel-read-chunk-size lines waits for the one before it.
+    #[structopt(long)]
+    deterministic: bool,
+
+    /// Read input on a thread of its own, which hands batches of
+    /// --parallel-read-chunk-size lines to the other threads, instead of each
+    /// thread taking turns to read. Suits stdin and other pipes, which can't
+    /// be memory mapped. Has zero effect when --jobs=1.
+    #[structopt(long, conflicts_with = "deterministic")]
+    pipeline: bool,
 
     /// Controls the granularity of the clustering algorithm. Lower values of
     /// max_distance will increase the granularity of clustering.
@@ -33,26 +91,545 @@ struct Options {
     #[structopt(long, default_value = "\\s+")]
     split_pattern: String,
 
+    /// How to split lines into tokens: "split" on --split-pattern, "find" to
+    /// use the matches of --token-pattern as tokens, "delimiters" to split on
+    /// runs of any of the --delimiters characters, or "quoted" to split like
+    /// "delimiters" but keep "quoted strings" and [bracketed groups] whole.
+    #[structopt(long, default_value = "split")]
+    tokenizer: TokenizerKind,
+
+    /// Regex matching each token, for --tokenizer find.
+    #[structopt(long, required_if("tokenizer", "find"))]
+    token_pattern: Option<String>,
+
+    /// ASCII characters which separate tokens, for --tokenizer delimiters and
+    /// quoted.
+    #[structopt(long, default_value = " \t")]
+    delimiters: String,
+
+    /// Also split tokens at each of these ASCII characters, keeping each one
+    /// as a token of its own, so that "user=123" and "user=456" become
+    /// "user=---". For example ":=,/".
+    #[structopt(long)]
+    keep_delimiters: Option<String>,
+
+    /// Only cluster the part of each line captured by the group named "msg"
+    /// of this regex. Other named groups are counted as dimensions of each
+    /// cluster, e.g. '^(?P<time>\S+) (?P<level>\w+) (?P<msg>.*)$'.
+    #[structopt(long)]
+    message_regex: Option<String>,
+
+    /// Only print the N largest clusters.
+    #[structopt(long)]
+    top: Option<usize>,
+
+    /// Print each pattern as an anchored regular expression, with a capture
+    /// group for each placeholder, instead of as text.
+    #[structopt(long, overrides_with = "no-regex")]
+    regex: bool,
+
+    /// Print patterns as text even if the --profile sets regex = true.
+    #[structopt(long, overrides_with = "regex")]
+    no_regex: bool,
+
+    /// Output format of the report: "text", "json" for a single JSON document,
+    /// "ndjson" for one JSON object per cluster per line, "csv" or "tsv" for
+    /// spreadsheets, or "markdown" for a Markdown table.
+    #[structopt(long, default_value = "text")]
+    format: Format,
+
+    /// Highlight placeholders and variables in text reports: "always",
+    /// "never", or "auto" to only use colors when stdout is a terminal.
+    #[structopt(long, default_value = "auto")]
+    color: ColorChoice,
+
+    /// Keep reading lines as they are appended to the file, surviving log
+    /// rotation and truncation, and print a report every --interval seconds.
+    /// A file truncated and rewritten past the last read position is only
+    /// noticed if the bytes just before that position changed. Requires a file
+    /// path.
+    #[structopt(long, short)]
+    follow: bool,
+
+    /// Seconds between reports in --follow mode.
+    #[structopt(long, default_value = "5")]
+    interval: u64,
+
+    /// Resume from the clusterer state saved in this file, if it exists, and
+    /// save the updated state back to it when done. The saved max distance,
+    /// min members and split pattern take precedence over the command line.
+    /// Input is processed on a single thread so that new lines are added to
+    /// the saved clusters exactly as if they had been part of the original run.
+    #[structopt(long)]
+    state: Option<PathBuf>,
+
+    /// Instead of finding new patterns, count how many lines match each of
+    /// the patterns in this saved state file (see --state). Lines are never
+    /// used to create or generalize patterns, so counts are comparable
+    /// between runs.
+    #[structopt(long = "match", name = "match")]
+    match_state: Option<PathBuf>,
+
+    /// Only report lines which don't fit any of the patterns in this saved
+    /// state file (see --state), grouped into clusters of their own. Exits
+    /// with status 1 if any such lines are found.
+    #[structopt(long)]
+    novel: Option<PathBuf>,
+
+    /// In --match or --novel mode, write lines which didn't match any pattern
+    /// to this file.
+    #[structopt(long)]
+    unmatched: Option<PathBuf>,
+
+    /// Compare the patterns of the input with the patterns of this file.
+    /// Reports the patterns found only in the input, only in this file, and
+    /// those found in both whose share of the lines changed by at least
+    /// --min-change-ratio.
+    #[structopt(long)]
+    diff: Option<PathBuf>,
+
+    /// In --diff mode, how many times more frequent a pattern must be on one
+    /// side than the other to be reported.
+    #[structopt(long, default_value = "2")]
+    min_change_ratio: f64,
+
+    /// Write the id of the cluster each input line belongs to into this file,
+    /// and show cluster ids in the report. With --jobs other than 1 the input
+    /// is read a second time to assign lines to the final clusters, so it
+    /// must be a file rather than stdin, and the number of lines with each id
+    /// may differ from the counts in the report since some lines are assigned
+    /// to a different cluster than the one which counted them.
+    #[structopt(long, conflicts_with_all = &["state", "match", "novel", "diff", "follow"])]
+    annotate: Option<PathBuf>,
+
+    /// Layout of the --annotate file. "lines" writes every input line
+    /// prefixed with its cluster id and a tab, "index" writes a
+    /// "line_no<TAB>cluster_id" row for every input line.
+    #[structopt(long, default_value = "lines")]
+    annotate_format: AnnotationFormat,
+
+    /// Take the settings of this profile from the config file for any flags
+    /// which aren't given on the command line. A profile is a [profile.NAME]
+    /// table whose keys are flag names, such as max-distance = 0.4.
+    #[structopt(long)]
+    profile: Option<String>,
+
+    /// Config file to read --profile from. By default profiles are read from
+    /// $XDG_CONFIG_HOME/logmine/config.toml and then from .logmine.toml in
+    /// the current directory, whose profiles replace those of the same name.
+    #[structopt(long, requires = "profile")]
+    config: Option<PathBuf>,
+
+    /// What to do with lines which are not valid UTF-8: "skip" them, decode
+    /// them "lossy" by replacing invalid bytes with U+FFFD, or "fail". The
+    /// number of such lines is printed to stderr.
+    #[structopt(long, default_value = "lossy")]
+    invalid_utf8: InvalidUtf8,
+
+    /// Cluster lines as raw bytes rather than as UTF-8 text, for logs mixing
+    /// encodings or containing binary data. Lines are only equal when their
+    /// bytes are, and --split-pattern and --token-pattern are matched against
+    /// the bytes. Patterns are decoded as UTF-8 for output, replacing invalid
+    /// sequences with U+FFFD. Not supported with saved state, which holds
+    /// text.
+    #[structopt(long, conflicts_with_all = &["state", "match", "novel", "invalid-utf8"])]
+    bytes: bool,
+
     /// Path to the file to read. Will read from stdin if not specified.
     file: Option<PathBuf>,
 }
 
+#[derive(Clone, Copy, PartialEq)]
+enum TokenizerKind {
+    Split,
+    Find,
+    Delimiters,
+    Quoted,
+}
+
+impl FromStr for TokenizerKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "split" => Ok(Self::Split),
+            "find" => Ok(Self::Find),
+            "delimiters" => Ok(Self::Delimiters),
+            "quoted" => Ok(Self::Quoted),
+            _ => Err(format!(
+                "unknown tokenizer {:?}, expected split, find, delimiters or quoted",
+                s
+            )),
+        }
+    }
+}
+
+/// How long to wait before checking a followed file for more data after
+/// reaching its end
+const FOLLOW_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Exit status when the input couldn't be processed
+const ERROR_EXIT_CODE: i32 = 2;
+
 fn main() {
-    let opts = Options::from_args();
+    let matches = Options::clap().get_matches();
+    let opts = Options::from_clap(&matches);
+    let decoder = if opts.bytes {
+        LineDecoder::bytes()
+    } else {
+        LineDecoder::new(opts.invalid_utf8)
+    };
 
-    let split_regex = Regex::new(&opts.split_pattern).unwrap();
+    let result = run(opts, &matches, &decoder);
 
+    let invalid_lines = decoder.invalid_lines();
+    let action = match decoder.policy() {
+        InvalidUtf8::Skip => "skipped",
+        InvalidUtf8::Lossy => "decoded with replacement characters",
+        InvalidUtf8::Fail => "",
+    };
+    if invalid_lines > 0 && !action.is_empty() {
+        eprintln!(
+            "warning: {} lines were not valid UTF-8 and were {}",
+            invalid_lines, action
+        );
+    }
+
+    match result {
+        Ok(code) => std::process::exit(code),
+        Err(e) => {
+            eprintln!("error: {}", e);
+            std::process::exit(ERROR_EXIT_CODE);
+        }
+    }
+}
+
+/// Run the mode chosen by `opts`, returning the exit status
+fn run(mut opts: Options, matches: &ArgMatches, decoder: &LineDecoder) -> Result<i32, Error> {
+    if let Some(name) = opts.profile.clone() {
+        let config = match &opts.config {
+            Some(path) => Config::read(path)?,
+            None => Config::read_default()?,
+        };
+        let profile = config
+            .profile
+            .get(&name)
+            .ok_or_else(|| Error::Config(format!("no profile named {:?}", name)))?;
+
+        apply_profile(&mut opts, matches, profile)?;
+    }
+
+    let tokenizer = tokenizer(&opts)?;
+
+    // every cluster is kept while clustering so that the report can give
+    // percentages of all lines, and small clusters are left out of the report
     let clusterer_options = ClustererOptions::default()
         .with_max_dist(opts.max_distance)
-        .with_min_members(opts.min_members);
+        .with_min_members(1);
+
+    let report = ReportOptions {
+        format: opts.format,
+        top: opts.top,
+        min_members: opts.min_members,
+        show_ids: opts.annotate.is_some(),
+        as_regex: opts.regex,
+        keep_order: false,
+        color: opts.color.for_stdout(),
+    };
+
+    if opts.follow {
+        let file_path = opts
+            .file
+            .ok_or_else(|| Error::Config("--follow requires a file path".to_string()))?;
+        return follow(
+            clusterer_options,
+            tokenizer,
+            file_path,
+            decoder,
+            Duration::from_secs(opts.interval),
+            &report,
+        );
+    }
+
+    let (file, progress_bar) = open_input(opts.file.clone())?;
+
+    if let Some(match_state) = &opts.match_state {
+        let clusterer = load_state(match_state)?;
+        let mut matcher = Matcher::from_clusterer(&clusterer);
+
+        filter_unmatched(
+            file,
+            decoder,
+            progress_bar.clone(),
+            opts.unmatched.as_deref(),
+            |line| matcher.process_line(line).is_none(),
+        )?;
+        progress_bar.finish_at_current_pos();
+
+        // every saved pattern is reported, in its saved order, so that
+        // reports from different inputs line up
+        let report = ReportOptions {
+            min_members: 0,
+            keep_order: true,
+            ..report
+        };
+        print_report(
+            Report::new(matcher.clusters(), &report).with_unmatched(matcher.unmatched() as u64),
+            &report,
+            clusterer.tokenizer(),
+        )?;
+
+        return Ok(0);
+    }
+
+    if let Some(baseline_path) = &opts.novel {
+        let baseline = load_state(baseline_path)?;
+        let mut detector = NoveltyDetector::from_clusterer(&baseline);
+
+        filter_unmatched(
+            file,
+            decoder,
+            progress_bar.clone(),
+            opts.unmatched.as_deref(),
+            |line| detector.process_line(line),
+        )?;
+        progress_bar.finish_at_current_pos();
+
+        let clusters: Vec<_> = detector.take_novel().collect();
+        print_report(
+            Report::new(&clusters, &report).with_total_lines(detector.total_lines()),
+            &report,
+            baseline.tokenizer(),
+        )?;
+
+        return Ok(if detector.novel_lines() > 0 { 1 } else { 0 });
+    }
+
+    if let Some(other_path) = &opts.diff {
+        let a = find_clusters(
+            &opts,
+            clusterer_options,
+            file,
+            decoder,
+            progress_bar,
+            tokenizer.clone(),
+        )?;
+
+        let (other, progress_bar) = open_input(Some(other_path.clone()))?;
+        let b = find_clusters(
+            &opts,
+            clusterer_options,
+            other,
+            decoder,
+            progress_bar,
+            tokenizer.clone(),
+        )?;
+
+        let result = diff::diff(
+            a,
+            b,
+            clusterer_options.with_min_members(opts.min_members),
+            opts.min_change_ratio,
+        );
+
+        let stdout = std::io::stdout();
+        report::write_diff(
+            stdout.lock(),
+            &result,
+            &other_path.display().to_string(),
+            &report,
+            &tokenizer,
+        )?;
+
+        return Ok(0);
+    }
 
-    let (file_path, is_stdin) = match opts.file {
+    if let Some(annotate_path) = &opts.annotate {
+        let mut annotations = Annotations::new(
+            BufWriter::new(File::create(annotate_path)?),
+            opts.annotate_format,
+        );
+
+        let clusters = if jobs(&opts) == 1 {
+            let mut clusterer = Clusterer::new(clusterer_options, tokenizer.clone());
+            logmine_rs::process_reader_annotated(
+                &mut clusterer,
+                file,
+                decoder,
+                progress_bar.clone(),
+                |line, cluster_id| annotations.write(Some(cluster_id), line.as_bytes()),
+            )?;
+            progress_bar.finish_at_current_pos();
+
+            clusterer.take_result().collect()
+        } else {
+            let file_path = opts.file.clone().ok_or_else(|| {
+                Error::Config("--annotate needs a file path unless --jobs=1".to_string())
+            })?;
+            let clusters = find_clusters(
+                &opts,
+                clusterer_options,
+                file,
+                decoder,
+                progress_bar,
+                tokenizer.clone(),
+            )?;
+
+            let mut annotator = Annotator::new(&clusters, opts.max_distance, tokenizer.clone());
+            let (file, progress_bar) = open_input(Some(file_path))?;
+            for_each_line(file, decoder, progress_bar.clone(), |line| {
+                let cluster_id = match line {
+                    Line::Text(text) => annotator.cluster_id(text),
+                    Line::Bytes(bytes) => annotator.bytes_cluster_id(bytes),
+                };
+                annotations.write(cluster_id, line.as_bytes())
+            })?;
+            progress_bar.finish_at_current_pos();
+
+            clusters
+        };
+
+        annotations.into_inner()?;
+
+        print_report(Report::new(&clusters, &report), &report, &tokenizer)?;
+
+        return Ok(0);
+    }
+
+    if let Some(state_path) = &opts.state {
+        let split_regex = match tokenizer {
+            BuiltinTokenizer::Split(split_regex) => split_regex,
+            _ => {
+                let message = "--state only supports --tokenizer split";
+                return Err(Error::Config(message.to_string()));
+            }
+        };
+
+        let mut clusterer = if state_path.exists() {
+            load_state(state_path)?
+        } else {
+            Clusterer::new(
+                clusterer_options.with_min_members(opts.min_members),
+                split_regex,
+            )
+        };
+
+        logmine_rs::process_reader(&mut clusterer, file, decoder, progress_bar.clone())?;
+        progress_bar.finish_at_current_pos();
+        save_state(&clusterer, state_path)?;
+
+        let report = ReportOptions {
+            min_members: clusterer.options().min_members,
+            ..report
+        };
+        print_report(
+            Report::new(clusterer.all_clusters(), &report),
+            &report,
+            clusterer.tokenizer(),
+        )?;
+
+        return Ok(0);
+    }
+
+    let clusters = find_clusters(
+        &opts,
+        clusterer_options,
+        file,
+        decoder,
+        progress_bar,
+        tokenizer.clone(),
+    )?;
+
+    print_report(Report::new(&clusters, &report), &report, &tokenizer)?;
+
+    Ok(0)
+}
+
+/// Use the settings of `profile` for each flag which wasn't given in `matches`
+fn apply_profile(opts: &mut Options, matches: &ArgMatches, profile: &Profile) -> Result<(), Error> {
+    macro_rules! apply {
+        ($field:ident) => {
+            apply!($field, value => value.clone().into())
+        };
+        ($field:ident, parse) => {
+            apply!($field, value => value.parse().map_err(Error::Config)?)
+        };
+        ($field:ident, $value:ident => $convert:expr) => {
+            if let Some($value) = &profile.$field {
+                // clap names arguments after their long flag
+                if matches.occurrences_of(stringify!($field).replace('_', "-")) == 0 {
+                    opts.$field = $convert;
+                }
+            }
+        };
+    }
+
+    apply!(split_pattern);
+    apply!(tokenizer, parse);
+    apply!(token_pattern);
+    apply!(delimiters);
+    apply!(keep_delimiters);
+    apply!(message_regex);
+    apply!(max_distance);
+    apply!(min_members);
+    apply!(top);
+    if !opts.no_regex {
+        apply!(regex);
+    }
+    apply!(format, parse);
+    apply!(color, parse);
+
+    Ok(())
+}
+
+fn tokenizer(opts: &Options) -> Result<BuiltinTokenizer, Error> {
+    let tokenizer = match opts.tokenizer {
+        TokenizerKind::Split if opts.bytes => {
+            BuiltinTokenizer::Bytes(BytesRegex(regex::bytes::Regex::new(&opts.split_pattern)?))
+        }
+        TokenizerKind::Split => BuiltinTokenizer::Split(Regex::new(&opts.split_pattern)?),
+        TokenizerKind::Find => {
+            let token_pattern = opts.token_pattern.as_ref().ok_or_else(|| {
+                Error::Config("--tokenizer find requires --token-pattern".to_string())
+            })?;
+            BuiltinTokenizer::Find(RegexFind::new(Regex::new(token_pattern)?))
+        }
+        TokenizerKind::Delimiters => {
+            BuiltinTokenizer::Delimiters(Delimiters::new(&opts.delimiters).map_err(Error::Config)?)
+        }
+        TokenizerKind::Quoted => BuiltinTokenizer::QuoteAware(QuoteAware::new(
+            Delimiters::new(&opts.delimiters).map_err(Error::Config)?,
+        )),
+    };
+
+    let tokenizer = match &opts.keep_delimiters {
+        Some(delimiters) => BuiltinTokenizer::KeepDelimiters(Box::new(KeepDelimiters::new(
+            tokenizer,
+            Delimiters::new(delimiters).map_err(Error::Config)?,
+        ))),
+        None => tokenizer,
+    };
+
+    Ok(match &opts.message_regex {
+        Some(regex) => {
+            let message =
+                MessageRegex::new(Regex::new(regex)?, tokenizer).map_err(Error::Config)?;
+            BuiltinTokenizer::Message(Box::new(message))
+        }
+        None => tokenizer,
+    })
+}
+
+/// Open the file at `path`, or stdin if there is no path, along with a
+/// progress bar for reading it
+fn open_input(path: Option<PathBuf>) -> Result<(BufReader<File>, ProgressBar), Error> {
+    let (file_path, is_stdin) = match path {
         Some(file) => (file, false),
         None => ("/dev/stdin".into(), true),
     };
 
-    let file = File::open(file_path).unwrap();
-    let filesize_bytes = file.metadata().unwrap().len();
+    let file = File::open(&file_path).map_err(|e| with_path(e, &file_path))?;
+    let filesize_bytes = file.metadata()?.len();
 
     let progress_bar = if is_stdin {
         let bar = ProgressBar::new_spinner();
@@ -69,32 +646,297 @@ fn main() {
         bar
     };
 
-    let file = BufReader::new(file);
+    Ok((BufReader::new(file), progress_bar))
+}
 
-    let jobs = opts.jobs.unwrap_or_else(num_cpus::get_physical);
+/// Add the path of the file which couldn't be opened to `e`
+fn with_path(e: io::Error, path: &Path) -> Error {
+    io::Error::new(e.kind(), format!("{}: {}", path.display(), e)).into()
+}
 
-    let mut clusters = if jobs == 1 {
-        logmine_rs::main_single_core(clusterer_options, file, progress_bar.clone(), split_regex)
-    } else {
-        logmine_rs::parallel_clusterer::run(
+fn load_state(path: &Path) -> Result<Clusterer, Error> {
+    let file = File::open(path).map_err(|e| with_path(e, path))?;
+    Clusterer::load(BufReader::new(file)).map_err(|e| with_path(e, path))
+}
+
+fn jobs(opts: &Options) -> usize {
+    opts.jobs.unwrap_or_else(num_cpus::get_physical)
+}
+
+/// Cluster every line of `file`, in parallel unless --jobs=1
+fn find_clusters(
+    opts: &Options,
+    clusterer_options: ClustererOptions,
+    file: BufReader<File>,
+    decoder: &LineDecoder,
+    progress_bar: ProgressBar,
+    tokenizer: BuiltinTokenizer,
+) -> Result<Vec<Cluster<'static>>, Error> {
+    let jobs = jobs(opts);
+    let mut chunk_sizes = None;
+
+    let clusters = if jobs == 1 {
+        logmine_rs::main_single_core(
             clusterer_options,
-            opts.parallel_read_chunk_size,
             file,
+            decoder,
             progress_bar.clone(),
-            split_regex,
-            ThreadPoolBuilder::new()
-                .num_threads(jobs)
-                .thread_name(|i| format!("logmine-wrk-{}", i))
-                .build()
-                .unwrap(),
-        )
+            tokenizer,
+        )?
+    } else {
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(jobs)
+            .thread_name(|i| format!("logmine-wrk-{}", i))
+            .build()
+            .map_err(io::Error::other)?;
+
+        if opts.deterministic {
+            logmine_rs::parallel_clusterer::run_deterministic(
+                clusterer_options,
+                opts.parallel_read_chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE),
+                file,
+                decoder,
+                progress_bar.clone(),
+                tokenizer,
+                pool,
+            )?
+        } else if opts.pipeline {
+            logmine_rs::parallel_clusterer::run_pipelined(
+                clusterer_options,
+                opts.parallel_read_chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE),
+                file,
+                decoder,
+                progress_bar.clone(),
+                tokenizer,
+                pool,
+            )?
+        } else if opts.mmap && file.get_ref().metadata()?.is_file() {
+            logmine_rs::parallel_clusterer::run_mapped(
+                clusterer_options,
+                file.get_ref(),
+                decoder,
+                progress_bar.clone(),
+                tokenizer,
+                pool,
+            )?
+        } else {
+            let chunk_size = match opts.parallel_read_chunk_size {
+                Some(size) => ChunkSize::Fixed(size),
+                None => ChunkSize::Adaptive(AdaptiveChunkSize::new(
+                    opts.min_chunk_size,
+                    opts.max_chunk_size,
+                )),
+            };
+
+            let clusters = logmine_rs::parallel_clusterer::run(
+                clusterer_options,
+                chunk_size.clone(),
+                file,
+                decoder,
+                progress_bar.clone(),
+                tokenizer,
+                pool,
+            )?;
+            chunk_sizes = Some(chunk_size);
+
+            clusters
+        }
     };
 
     progress_bar.finish_at_current_pos();
 
-    clusters.sort_by(|c1, c2| c2.count.cmp(&c1.count));
+    if opts.verbose {
+        match chunk_sizes {
+            Some(ChunkSize::Adaptive(adaptive)) => {
+                let stats = adaptive.stats();
+                eprintln!(
+                    "chunk sizes: {} chunks of {} to {} lines, {:.0} on average, {} last",
+                    stats.chunks,
+                    stats.smallest,
+                    stats.largest,
+                    stats.mean(),
+                    stats.last
+                );
+            }
+            Some(ChunkSize::Fixed(size)) => eprintln!("chunk sizes: fixed at {} lines", size),
+            None => {}
+        }
+    }
+
+    Ok(clusters)
+}
+
+/// Write the state to a temporary file first so that an interrupted save
+/// doesn't destroy the previous state.
+fn save_state(clusterer: &Clusterer, state_path: &Path) -> io::Result<()> {
+    let mut tmp_path = state_path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    clusterer.save(&mut writer)?;
+    writer.flush()?;
+    drop(writer);
+
+    std::fs::rename(tmp_path, state_path)
+}
+
+/// Run `is_unmatched` on every line, writing the lines for which it returns
+/// `true` to `unmatched_path` if one was given
+fn filter_unmatched(
+    file: impl BufRead,
+    decoder: &LineDecoder,
+    progress: ProgressBar,
+    unmatched_path: Option<&Path>,
+    mut is_unmatched: impl FnMut(&str) -> bool,
+) -> Result<(), Error> {
+    let mut unmatched = match unmatched_path {
+        Some(path) => Some(BufWriter::new(File::create(path)?)),
+        None => None,
+    };
+
+    for_each_line(file, decoder, progress, |line| {
+        if is_unmatched(&line.to_str_lossy()) {
+            if let Some(unmatched) = &mut unmatched {
+                unmatched.write_all(line.as_bytes())?;
+            }
+        }
+        Ok(())
+    })?;
+
+    if let Some(mut unmatched) = unmatched {
+        unmatched.flush()?;
+    }
+
+    Ok(())
+}
+
+fn for_each_line(
+    mut file: impl BufRead,
+    decoder: &LineDecoder,
+    progress: ProgressBar,
+    mut f: impl FnMut(&Line) -> io::Result<()>,
+) -> Result<(), Error> {
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let size = file.read_until(b'\n', &mut line)?;
+        if size == 0 {
+            return Ok(());
+        }
+
+        if let Some(line) = decoder.decode(&line)? {
+            f(&line)?;
+        }
+        progress.inc(size as u64);
+    }
+}
+
+/// Print a report of clusters which were found by splitting lines with
+/// `tokenizer`
+fn print_report(
+    report: Report,
+    options: &ReportOptions,
+    tokenizer: &dyn Tokenizer,
+) -> io::Result<()> {
+    let stdout = std::io::stdout();
+    report.write(stdout.lock(), options, tokenizer)
+}
+
+/// Feed lines appended to `file_path` into a single long-lived clusterer,
+/// printing the current report every `interval`. When stdout is a terminal the
+/// previous report is replaced rather than scrolled.
+fn follow(
+    options: ClustererOptions,
+    tokenizer: BuiltinTokenizer,
+    file_path: PathBuf,
+    decoder: &LineDecoder,
+    interval: Duration,
+    report: &ReportOptions,
+) -> Result<i32, Error> {
+    let mut clusterer = Clusterer::new(options, tokenizer);
+    let mut follower = Follower::open(&file_path).map_err(|e| with_path(e, &file_path))?;
+    let stdout = Term::stdout();
+
+    let mut line = Vec::new();
+    let mut last_report = Instant::now();
+
+    loop {
+        let got_line = follower.read_line(&mut line)?;
+        if got_line {
+            if let Some(line) = decoder.decode(&line)? {
+                clusterer.process(&line);
+            }
+        }
+
+        if last_report.elapsed() >= interval {
+            if stdout.is_term() {
+                stdout.clear_screen()?;
+            }
+            print_report(
+                Report::new(clusterer.all_clusters(), report),
+                report,
+                clusterer.tokenizer(),
+            )?;
+
+            last_report = Instant::now();
+        }
+
+        if !got_line {
+            std::thread::sleep(FOLLOW_POLL_INTERVAL);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use structopt::StructOpt;
+
+    use logmine_rs::config::Config;
+
+    use super::{apply_profile, Options};
+
+    /// Options parsed from `args`, with the settings of `profile` applied
+    fn with_profile(args: &[&str], profile: &str) -> Options {
+        let config = Config::parse(&format!("[profile.test]\n{}", profile)).unwrap();
+        let matches = Options::clap().get_matches_from(args);
+        let mut opts = Options::from_clap(&matches);
+
+        apply_profile(&mut opts, &matches, &config.profile["test"]).unwrap();
+        opts
+    }
+
+    #[test]
+    fn test_profile_fills_in_missing_flags() {
+        let opts = with_profile(
+            &["logmine", "--max-distance", "0.2"],
+            "max-distance = 0.5\nmin-members = 7\nsplit-pattern = ','",
+        );
+
+        assert_eq!(opts.max_distance, 0.2);
+        assert_eq!(opts.min_members, 7);
+        assert_eq!(opts.split_pattern, ",");
+    }
+
+    #[test]
+    fn test_no_regex_overrides_profile() {
+        assert!(with_profile(&["logmine"], "regex = true").regex);
+        assert!(!with_profile(&["logmine", "--no-regex"], "regex = true").regex);
+        assert!(!with_profile(&["logmine", "--regex", "--no-regex"], "regex = true").regex);
+        assert!(with_profile(&["logmine", "--no-regex", "--regex"], "regex = false").regex);
+    }
+
+    #[test]
+    fn test_bytes_conflicts() {
+        let parse = |args: &[&str]| Options::clap().get_matches_from_safe(args);
 
-    for c in clusters {
-        println!("{}", c);
+        // --invalid-utf8 only conflicts when given, not through its default
+        assert!(parse(&["logmine", "--bytes"]).is_ok());
+        for flag in &["--state", "--match", "--novel", "--invalid-utf8"] {
+            assert!(
+                parse(&["logmine", "--bytes", flag, "skip"]).is_err(),
+                "{}",
+                flag
+            );
+        }
     }
 }
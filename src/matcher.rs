@@ -0,0 +1,234 @@
+use std::ops::Range;
+
+use regex::Regex;
+
+use crate::{
+    clusterer::{record_fields, Cluster, Clusterer, ClustererOptions},
+    pattern::Pattern,
+    scoring,
+    tokenizer::Tokenizer,
+};
+
+/// Classifies lines against a fixed set of clusters. Unlike `Clusterer`, a
+/// `Matcher` never creates new clusters or generalizes existing patterns, so
+/// counts from different inputs can be compared with each other.
+pub struct Matcher<T = Regex> {
+    clusters: Vec<Cluster<'static>>,
+    unmatched: u32,
+    max_dist: f64,
+    tokenizer: T,
+    pattern_backing_storage: Pattern<'static>,
+    field_backing_storage: Vec<(usize, Range<usize>)>,
+}
+
+impl<T: Tokenizer + Clone> Matcher<T> {
+    /// Lines are compared to the representative of each cluster, in the same
+    /// way as `Clusterer`. The counts and dimensions of `clusters` are reset.
+    pub fn new(
+        clusters: impl IntoIterator<Item = Cluster<'static>>,
+        max_dist: f64,
+        tokenizer: T,
+    ) -> Self {
+        Self {
+            clusters: clusters
+                .into_iter()
+                .map(|c| Cluster {
+                    count: 0,
+                    dimensions: Default::default(),
+                    ..c
+                })
+                .collect(),
+            unmatched: 0,
+            max_dist,
+            tokenizer,
+            pattern_backing_storage: Default::default(),
+            field_backing_storage: Default::default(),
+        }
+    }
+
+    /// Match against the clusters in `clusterer` which meet its
+    /// `min_members`, using its max distance and tokenizer.
+    pub fn from_clusterer(clusterer: &Clusterer<T>) -> Self {
+        Self::new(
+            clusterer.clusters().cloned(),
+            clusterer.options().max_dist,
+            clusterer.tokenizer().clone(),
+        )
+    }
+
+    /// Find the cluster closest to `line` and count the line towards it.
+    /// Returns the index of that cluster, or `None` if no cluster is within
+    /// the max distance.
+    pub fn process_line(&mut self, line: &str) -> Option<usize> {
+        let mut pattern = std::mem::take(&mut self.pattern_backing_storage).clear_and_reinterpret();
+        let mut fields = std::mem::take(&mut self.field_backing_storage);
+        fields.clear();
+        pattern.push_line_with_fields(line, &self.tokenizer, &mut |index, value| {
+            fields.push((index, value))
+        });
+
+        let mut best: Option<(usize, f64)> = None;
+        for (i, cluster) in self.clusters.iter().enumerate() {
+            // a max distance of 0 disables the early return in `distance`, so
+            // that the exact distance can be compared between clusters
+            let dist = scoring::distance(&cluster.representative, &pattern, 0.0);
+
+            let is_best = match best {
+                Some((_, best_dist)) => dist < best_dist,
+                None => true,
+            };
+
+            if dist <= self.max_dist && is_best {
+                best = Some((i, dist));
+            }
+        }
+
+        self.pattern_backing_storage = pattern.clear_and_reinterpret();
+
+        if let Some((i, _)) = best {
+            self.clusters[i].count += 1;
+            record_fields(&self.tokenizer, &mut self.clusters[i], line, &fields);
+        } else {
+            self.unmatched += 1;
+        }
+
+        self.field_backing_storage = fields;
+        best.map(|(i, _)| i)
+    }
+
+    /// The clusters being matched against, in their original order. The count
+    /// of each is the number of lines matched to it.
+    pub fn clusters(&self) -> &[Cluster<'static>] {
+        &self.clusters
+    }
+
+    /// Number of lines which didn't match any pattern
+    pub fn unmatched(&self) -> u32 {
+        self.unmatched
+    }
+}
+
+/// Finds lines which don't fit any cluster of a baseline, and groups those
+/// novel lines into clusters of their own.
+pub struct NoveltyDetector<T = Regex> {
+    baseline: Matcher<T>,
+    novel: Clusterer<T>,
+}
+
+impl<T: Tokenizer + Clone> NoveltyDetector<T> {
+    /// Novel lines are clustered with the max distance and tokenizer of
+    /// `baseline`. Every novel cluster is kept, however small.
+    pub fn from_clusterer(baseline: &Clusterer<T>) -> Self {
+        let options = baseline.options().with_min_members(1);
+
+        Self {
+            baseline: Matcher::from_clusterer(baseline),
+            novel: Clusterer::new(options, baseline.tokenizer().clone()),
+        }
+    }
+
+    pub fn new(baseline: Matcher<T>, novel_options: ClustererOptions) -> Self {
+        let tokenizer = baseline.tokenizer.clone();
+
+        Self {
+            baseline,
+            novel: Clusterer::new(novel_options, tokenizer),
+        }
+    }
+
+    /// Returns `true` if `line` is not within the max distance of any baseline
+    /// cluster.
+    pub fn process_line(&mut self, line: &str) -> bool {
+        if self.baseline.process_line(line).is_some() {
+            return false;
+        }
+
+        self.novel.process_line(line);
+        true
+    }
+
+    /// Number of lines which didn't fit the baseline
+    pub fn novel_lines(&self) -> u32 {
+        self.baseline.unmatched()
+    }
+
+    /// Number of lines processed, novel or not
+    pub fn total_lines(&self) -> u64 {
+        let matched: u64 = self
+            .baseline
+            .clusters()
+            .iter()
+            .map(|c| c.count as u64)
+            .sum();
+
+        matched + self.novel_lines() as u64
+    }
+
+    /// Clusters of the lines which didn't fit the baseline
+    pub fn take_novel(&mut self) -> impl Iterator<Item = Cluster<'static>> {
+        self.novel.take_result()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use regex::Regex;
+
+    use crate::{clusterer::Cluster, pattern::Pattern};
+
+    use super::{Matcher, NoveltyDetector};
+
+    fn cluster(representative: Pattern<'static>) -> Cluster<'static> {
+        Cluster {
+            pattern: representative.clone(),
+            representative,
+            count: 10,
+            dimensions: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_matches_best_cluster_without_learning() {
+        let mut matcher = Matcher::new(
+            vec![
+                cluster(Pattern::new(vec_into!["hello", "0", "y", "3"])),
+                cluster(Pattern::new(vec_into!["hello", "1", "z", "3"])),
+            ],
+            0.5,
+            Regex::new("\\s+").unwrap(),
+        );
+
+        assert_eq!(matcher.process_line("hello 1 x 3"), Some(1));
+        assert_eq!(matcher.process_line("hello 2 y 3"), Some(0));
+        assert_eq!(matcher.process_line("abc m n q"), None);
+        assert_eq!(matcher.process_line("abc m n q"), None);
+
+        let counts: Vec<_> = matcher.clusters().iter().map(|c| c.count).collect();
+        assert_eq!(counts, vec![1, 1]);
+        assert_eq!(matcher.unmatched(), 2);
+        assert_eq!(
+            matcher.clusters()[0].pattern,
+            Pattern::new(vec_into!["hello", "0", "y", "3"])
+        );
+    }
+
+    #[test]
+    fn test_novelty() {
+        let baseline = Matcher::new(
+            vec![cluster(Pattern::new(vec_into!["hello", "0", "y", "3"]))],
+            0.5,
+            Regex::new("\\s+").unwrap(),
+        );
+        let mut detector = NoveltyDetector::new(baseline, Default::default());
+
+        assert!(!detector.process_line("hello 1 x 3"));
+        assert!(detector.process_line("abc m n q"));
+        assert!(detector.process_line("abc m n q"));
+        assert!(detector.process_line("xyz"));
+
+        assert_eq!(detector.novel_lines(), 3);
+
+        let novel: Vec<_> = detector.take_novel().map(|c| c.count).collect();
+        assert_eq!(novel, vec![2, 1]);
+    }
+}
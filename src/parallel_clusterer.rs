@@ -1,82 +1,666 @@
 use indicatif::ProgressBar;
-use parking_lot::Mutex;
-use regex::Regex;
-use std::{io::BufRead, sync::Arc};
+use memmap2::Mmap;
+use parking_lot::{Condvar, Mutex};
+use std::{
+    cmp::Reverse,
+    fs::File,
+    io::BufRead,
+    ops::Range,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
 
-use crossbeam_channel::Sender;
-use rayon::ThreadPool;
+use crossbeam_channel::{Receiver, Sender};
+use rayon::{prelude::*, ThreadPool};
 
 use crate::{
-    clusterer::{Cluster, Clusterer, ClustererOptions},
+    candidates::LengthIndex,
+    chunk_size::{ChunkMeasurement, ChunkSize, ChunkSizer},
+    clusterer::{self, Cluster, ClusterKeys, Clusterer, ClustererOptions},
+    error::Result,
+    input::{Line, LineDecoder},
+    pattern::Pattern,
     pool::StringPool,
     scoring,
+    symbols::{Symbol, Symbols},
+    tokenizer::Tokenizer,
 };
 
 /// Number of times each IO thread will attempt to steal the lock on the file
 /// between CPU-bound work
 const LOCK_STEAL_ATTEMPTS: usize = 4;
 
-pub fn run(
+/// Number of lines each thread of `run_mapped` processes between updates of
+/// the progress bar
+const MAPPED_PROGRESS_LINES: usize = 1000;
+
+/// A cluster found by one thread, with its symbols in the table shared by
+/// every thread of the run
+type KeyedCluster = (Cluster<'static>, ClusterKeys);
+
+/// Handle for viewing the clusters found so far by a call to
+/// `run_with_snapshots` while it is still in progress. Cloning the handle
+/// gives another handle to the same run.
+#[derive(Clone, Default)]
+pub struct Snapshots {
+    shared: Arc<SnapshotShared>,
+}
+
+#[derive(Default)]
+struct SnapshotShared {
+    /// Incremented every time a snapshot is requested. Workers compare this
+    /// against the last request they answered between chunks of lines.
+    requested: AtomicU64,
+    state: Mutex<SnapshotState>,
+    answered: Condvar,
+}
+
+#[derive(Default)]
+struct SnapshotState {
+    options: Option<ClustererOptions>,
+    workers: Vec<WorkerSnapshot>,
+}
+
+#[derive(Default)]
+struct WorkerSnapshot {
+    answered: u64,
+    finished: bool,
+    clusters: Vec<KeyedCluster>,
+}
+
+impl Snapshots {
+    /// Collect the clusters found so far by every worker and merge them in the
+    /// same way as the final result. Clusters are filtered by `min_members`
+    /// and sorted largest first. Blocks until each worker reaches the end of
+    /// the chunk of lines it is currently processing.
+    pub fn snapshot(&self) -> Vec<Cluster<'static>> {
+        let request = self.shared.requested.fetch_add(1, Ordering::SeqCst) + 1;
+
+        let mut state = self.shared.state.lock();
+        let options = match state.options {
+            Some(options) => options,
+            None => return Vec::new(),
+        };
+
+        while state
+            .workers
+            .iter()
+            .any(|w| !w.finished && w.answered < request)
+        {
+            self.shared.answered.wait(&mut state);
+        }
+
+        let results = state.workers.iter().map(|w| w.clusters.clone()).collect();
+        drop(state);
+
+        // the workers may still be busy on the run's pool, and the global
+        // pool is not ours to use, so merge on this thread
+        let mut clusters = merge_all(results, options, false);
+        clusters.sort_by_key(|c| Reverse(c.count));
+        clusters
+    }
+
+    fn start(&self, options: ClustererOptions, workers: usize) {
+        let mut state = self.shared.state.lock();
+        state.options = Some(options);
+        state.workers = (0..workers).map(|_| WorkerSnapshot::default()).collect();
+    }
+
+    /// Answer any outstanding snapshot request from `worker`. Returns the
+    /// number of the latest request answered.
+    fn publish<T: Tokenizer>(
+        &self,
+        worker: usize,
+        clusterer: &Clusterer<T>,
+        finished: bool,
+    ) -> u64 {
+        let request = self.shared.requested.load(Ordering::SeqCst);
+
+        let mut state = self.shared.state.lock();
+        let snapshot = &mut state.workers[worker];
+        if snapshot.answered >= request && !finished {
+            return snapshot.answered;
+        }
+
+        snapshot.clusters = clusterer.keyed_clusters();
+        snapshot.answered = request;
+        snapshot.finished = finished;
+        drop(state);
+
+        self.shared.answered.notify_all();
+        request
+    }
+
+    fn is_requested(&self, answered: u64) -> bool {
+        self.shared.requested.load(Ordering::Relaxed) > answered
+    }
+}
+
+/// Cluster the lines of `file` on every thread of `pool`. Threads take turns
+/// to read chunks of lines, of `read_chunk_size` lines or of a size adapted
+/// as the run goes (see `ChunkSize`), and the clusters found by each thread
+/// are merged at the end.
+pub fn run<T: Tokenizer + Clone + Send + Sync>(
     options: ClustererOptions,
-    read_chunk_size: usize,
+    read_chunk_size: impl Into<ChunkSize>,
     file: impl Sync + Send + BufRead,
+    decoder: &LineDecoder,
     progress: ProgressBar,
-    split_regex: Regex,
+    tokenizer: T,
     pool: ThreadPool,
-) -> Vec<Cluster<'static>> {
+) -> Result<Vec<Cluster<'static>>> {
+    run_inner(
+        options,
+        read_chunk_size.into(),
+        file,
+        decoder,
+        progress,
+        tokenizer,
+        pool,
+        None,
+    )
+}
+
+/// Same as `run`, but the clusters found so far can be viewed through
+/// `snapshots` while the run is in progress.
+#[allow(clippy::too_many_arguments)]
+pub fn run_with_snapshots<T: Tokenizer + Clone + Send + Sync>(
+    options: ClustererOptions,
+    read_chunk_size: impl Into<ChunkSize>,
+    file: impl Sync + Send + BufRead,
+    decoder: &LineDecoder,
+    progress: ProgressBar,
+    tokenizer: T,
+    pool: ThreadPool,
+    snapshots: &Snapshots,
+) -> Result<Vec<Cluster<'static>>> {
+    run_inner(
+        options,
+        read_chunk_size.into(),
+        file,
+        decoder,
+        progress,
+        tokenizer,
+        pool,
+        Some(snapshots),
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+fn run_inner<T: Tokenizer + Clone + Send + Sync>(
+    options: ClustererOptions,
+    read_chunk_size: ChunkSize,
+    file: impl Sync + Send + BufRead,
+    decoder: &LineDecoder,
+    progress: ProgressBar,
+    tokenizer: T,
+    pool: ThreadPool,
+    snapshots: Option<&Snapshots>,
+) -> Result<Vec<Cluster<'static>>> {
     let (tx, rx) = crossbeam_channel::bounded(pool.current_num_threads());
 
     let file = Arc::new(Mutex::new(file));
+    let symbols = Arc::new(Symbols::new());
+
+    if let Some(snapshots) = snapshots {
+        snapshots.start(options, pool.current_num_threads());
+    }
 
     pool.scope(|scope| {
-        for _ in 0..pool.current_num_threads() {
+        for worker in 0..pool.current_num_threads() {
             let tx = tx.clone();
             let file = file.clone();
+            let decoder = decoder.clone();
             let progress = progress.clone();
-            let split_regex = split_regex.clone();
+            let clusterer =
+                Clusterer::new(options, tokenizer.clone()).with_symbols(symbols.clone());
+            let snapshots = snapshots.map(|s| (worker, s.clone()));
+            let read_chunk_size = &read_chunk_size;
 
             scope.spawn(move |_| {
-                run_single_thread(tx, options, read_chunk_size, file, progress, split_regex);
+                run_single_thread(
+                    tx,
+                    clusterer,
+                    read_chunk_size,
+                    file,
+                    decoder,
+                    progress,
+                    snapshots,
+                );
             });
         }
 
         drop(tx);
     });
 
-    let mut total: Vec<Cluster<'static>> = Vec::new();
+    let results = rx.into_iter().collect::<Result<_>>()?;
+
+    Ok(pool.install(|| merge_all(results, options, true)))
+}
+
+/// Same as `run`, but for a regular file, which is memory mapped and split
+/// into one newline-aligned byte range per thread. Each thread clusters the
+/// lines of its range straight from the map, without taking a lock or copying
+/// lines other than those which are decoded lossily (see
+/// `LineDecoder::decode`). Results are merged in the order of the ranges.
+///
+/// As with any memory map, the file must not be truncated while this runs.
+pub fn run_mapped<T: Tokenizer + Clone + Send + Sync>(
+    options: ClustererOptions,
+    file: &File,
+    decoder: &LineDecoder,
+    progress: ProgressBar,
+    tokenizer: T,
+    pool: ThreadPool,
+) -> Result<Vec<Cluster<'static>>> {
+    // mapping an empty file fails on some platforms
+    if file.metadata()?.len() == 0 {
+        return Ok(Vec::new());
+    }
+
+    // Safety: the map is only read from, and the caller ensures that the file
+    // isn't truncated while it is mapped
+    let map = unsafe { Mmap::map(file)? };
+
+    let ranges = partition(&map, pool.current_num_threads());
+    let mut results: Vec<Result<Vec<KeyedCluster>>> =
+        ranges.iter().map(|_| Ok(Vec::new())).collect();
+    let symbols = Arc::new(Symbols::new());
+
+    pool.scope(|scope| {
+        for (range, result) in ranges.into_iter().zip(&mut results) {
+            let data = &map[range];
+            let decoder = decoder.clone();
+            let progress = progress.clone();
+            let clusterer =
+                Clusterer::new(options, tokenizer.clone()).with_symbols(symbols.clone());
+
+            scope.spawn(move |_| {
+                *result = cluster_mapped(clusterer, data, &decoder, &progress);
+            });
+        }
+    });
+
+    let results = results.into_iter().collect::<Result<_>>()?;
 
-    for thread_results in rx {
-        merge(&mut total, thread_results, options);
+    Ok(pool.install(|| merge_all(results, options, true)))
+}
+
+/// Split `data` into at most `parts` ranges of roughly equal size, each of
+/// which ends just after a newline or at the end of `data`
+fn partition(data: &[u8], parts: usize) -> Vec<Range<usize>> {
+    let mut ranges = Vec::with_capacity(parts);
+
+    let mut start = 0;
+    for part in 1..=parts {
+        if start >= data.len() {
+            break;
+        }
+
+        let target = (data.len() * part / parts).max(start);
+        let end = match data[target..].iter().position(|&b| b == b'\n') {
+            Some(newline) => target + newline + 1,
+            None => data.len(),
+        };
+
+        ranges.push(start..end);
+        start = end;
     }
 
-    total
+    ranges
 }
 
-fn fill(lines: &mut StringPool, reader: &mut impl BufRead) {
-    while let Some(mut line) = lines.take_dead() {
-        if reader.read_line(&mut *line).unwrap() == 0 {
-            line.stay_dead();
+/// Cluster every line of `data` with `clusterer` on the current thread
+fn cluster_mapped<T: Tokenizer>(
+    mut clusterer: Clusterer<T>,
+    data: &[u8],
+    decoder: &LineDecoder,
+    progress: &ProgressBar,
+) -> Result<Vec<KeyedCluster>> {
+    let mut size = 0;
+    for (i, bytes) in data.split_inclusive(|&b| b == b'\n').enumerate() {
+        if let Some(line) = decoder.decode(bytes)? {
+            clusterer.process(&line);
+        }
+
+        size += bytes.len();
+        if i % MAPPED_PROGRESS_LINES == MAPPED_PROGRESS_LINES - 1 {
+            progress.inc(size as u64);
+            size = 0;
+        }
+    }
+    progress.inc(size as u64);
+
+    Ok(clusterer.into_keyed_clusters())
+}
+
+/// Same as `run`, but the result is exactly that of `main_single_core`, no
+/// matter how many threads there are. Lines are read in chunks of
+/// `read_chunk_size`. The lines of a chunk are matched to the clusters of
+/// earlier chunks in parallel, since the representative of a cluster never
+/// changes. Lines which match none of them are then clustered in order, and
+/// finally the lines of each cluster are merged into its pattern, with
+/// clusters merged in parallel.
+///
+/// This is slower than `run` when most lines start new clusters, or when a
+/// few clusters get most of the lines.
+pub fn run_deterministic<T: Tokenizer + Send + Sync>(
+    options: ClustererOptions,
+    read_chunk_size: usize,
+    mut file: impl BufRead,
+    decoder: &LineDecoder,
+    progress: ProgressBar,
+    tokenizer: T,
+    pool: ThreadPool,
+) -> Result<Vec<Cluster<'static>>> {
+    let mut clusterer = Clusterer::new(options, tokenizer);
+
+    let mut lines: Vec<Vec<u8>> = Vec::new();
+    loop {
+        let mut len = 0;
+        let mut size = 0;
+        while len < read_chunk_size.max(1) {
+            if len == lines.len() {
+                lines.push(Vec::new());
+            }
+
+            lines[len].clear();
+            let read = file.read_until(b'\n', &mut lines[len])?;
+            if read == 0 {
+                break;
+            }
+            size += read;
+            len += 1;
+        }
+
+        if len == 0 {
             break;
         }
+
+        let mut decoded = Vec::with_capacity(len);
+        for line in &lines[..len] {
+            decoded.extend(decoder.decode(line)?);
+        }
+
+        pool.install(|| process_chunk_in_order(&mut clusterer, &decoded));
+        progress.inc(size as u64);
     }
+
+    Ok(clusterer.take_result().collect())
+}
+
+/// A line of a chunk, split up the same way as by `Clusterer::process`
+struct SplitLine<'l> {
+    /// The line if it is text, which is all `fields` need
+    line: &'l str,
+    pattern: Pattern<'l>,
+    /// As reported by `Tokenizer::tokenize_with_fields`
+    fields: Vec<(usize, Range<usize>)>,
+    keys: Vec<Symbol>,
 }
 
-fn run_single_thread(
-    tx: Sender<Vec<Cluster<'static>>>,
+/// Add `lines` to `clusterer`, with the same result as adding them one at a
+/// time with `Clusterer::process`
+fn process_chunk_in_order<T: Tokenizer + Send + Sync>(
+    clusterer: &mut Clusterer<T>,
+    lines: &[Line],
+) {
+    let known = clusterer.all_clusters().len();
+
+    let matched: Vec<(SplitLine, Option<usize>)> = lines
+        .par_iter()
+        .map(|line| {
+            let mut pattern = Pattern::default();
+            let mut fields = Vec::new();
+            let line = match line {
+                Line::Text(text) => {
+                    pattern.push_line_with_fields(
+                        text,
+                        clusterer.tokenizer(),
+                        &mut |index, value| fields.push((index, value)),
+                    );
+                    text
+                }
+                Line::Bytes(bytes) => {
+                    pattern.push_bytes(bytes, clusterer.tokenizer());
+                    ""
+                }
+            };
+            let keys = clusterer.lookup(&pattern);
+            let id = clusterer.find_cluster(&keys, 0..known);
+
+            let split = SplitLine {
+                line,
+                pattern,
+                fields,
+                keys,
+            };
+            (split, id)
+        })
+        .collect();
+
+    // lines of this chunk to add to each cluster, in order
+    let mut members: Vec<Vec<SplitLine>> = Vec::new();
+    for (mut split, id) in matched {
+        let id = match id {
+            Some(id) => id,
+            None => {
+                // clusters created by this chunk may have added symbols since
+                // the line was looked up
+                split.keys = clusterer.lookup(&split.pattern);
+
+                let created = clusterer.all_clusters().len();
+                match clusterer.find_cluster(&split.keys, known..created) {
+                    Some(id) => id,
+                    None => {
+                        clusterer.push_cluster(&split.pattern, split.line, &split.fields);
+                        continue;
+                    }
+                }
+            }
+        };
+
+        if members.len() <= id {
+            members.resize_with(id + 1, Vec::new);
+        }
+        members[id].push(split);
+    }
+
+    let (clusters, cluster_keys, tokenizer) = clusterer.parts_mut();
+    clusters[..members.len()]
+        .par_iter_mut()
+        .zip(&mut cluster_keys[..members.len()])
+        .zip(members)
+        .for_each(|((cluster, cluster_keys), members)| {
+            for split in members {
+                clusterer::add_line(cluster, cluster_keys, split.pattern, &split.keys);
+                clusterer::record_fields(tokenizer, cluster, split.line, &split.fields);
+            }
+        });
+}
+
+/// Same as `run`, but rather than every thread taking turns to read from
+/// `file`, a dedicated reader thread reads it into batches of
+/// `read_chunk_size` lines and sends them to the worker threads, which send
+/// the emptied batches back to be refilled. Workers never wait for each
+/// other, which suits inputs that are slow to read, such as pipes or
+/// decompressed streams.
+pub fn run_pipelined<T: Tokenizer + Clone + Send + Sync>(
     options: ClustererOptions,
     read_chunk_size: usize,
+    file: impl Send + BufRead,
+    decoder: &LineDecoder,
+    progress: ProgressBar,
+    tokenizer: T,
+    pool: ThreadPool,
+) -> Result<Vec<Cluster<'static>>> {
+    let workers = pool.current_num_threads();
+
+    // one batch for each worker to process while another is being filled
+    let (empty_tx, empty_rx) = crossbeam_channel::bounded(2 * workers);
+    for _ in 0..2 * workers {
+        let _ = empty_tx.send(StringPool::with_capacity(read_chunk_size.max(1)));
+    }
+    let (full_tx, full_rx) = crossbeam_channel::bounded(workers);
+
+    // only the workers may keep these ends open, so that the reader stops if
+    // they all exit
+    let channels: Vec<_> = (0..workers)
+        .map(|_| (full_rx.clone(), empty_tx.clone()))
+        .collect();
+    drop((full_rx, empty_tx));
+
+    let mut results: Vec<Result<Vec<KeyedCluster>>> =
+        (0..workers).map(|_| Ok(Vec::new())).collect();
+    let symbols = Arc::new(Symbols::new());
+
+    let read = std::thread::scope(|s| {
+        let reader = std::thread::Builder::new()
+            .name("logmine-reader".to_string())
+            .spawn_scoped(s, || read_batches(file, empty_rx, full_tx))?;
+
+        pool.scope(|scope| {
+            for (result, (full_rx, empty_tx)) in results.iter_mut().zip(channels) {
+                let decoder = decoder.clone();
+                let progress = progress.clone();
+                let mut clusterer =
+                    Clusterer::new(options, tokenizer.clone()).with_symbols(symbols.clone());
+
+                scope.spawn(move |_| {
+                    for mut lines in full_rx {
+                        let processed = process_batch(&mut clusterer, &mut lines, &decoder);
+
+                        // the reader stops taking batches once it reaches the
+                        // end of the file
+                        let _ = empty_tx.send(lines);
+
+                        match processed {
+                            Ok(size) => progress.inc(size as u64),
+                            Err(e) => {
+                                *result = Err(e);
+                                return;
+                            }
+                        }
+                    }
+
+                    *result = Ok(clusterer.into_keyed_clusters());
+                });
+            }
+        });
+
+        reader
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    });
+    read?;
+
+    let results = results.into_iter().collect::<Result<_>>()?;
+
+    Ok(pool.install(|| merge_all(results, options, true)))
+}
+
+/// Add every line of `lines` to `clusterer`. Returns their size in bytes.
+fn process_batch<T: Tokenizer>(
+    clusterer: &mut Clusterer<T>,
+    lines: &mut StringPool,
+    decoder: &LineDecoder,
+) -> Result<usize> {
+    let mut size = 0;
+    while let Some(line) = lines.take_live() {
+        if let Some(line) = decoder.decode(&line)? {
+            clusterer.process(&line);
+        }
+        size += line.len();
+    }
+
+    Ok(size)
+}
+
+/// Fill each empty batch of lines from `empty` with lines of `file` and send
+/// it to `full`, until the end of the file
+fn read_batches(
+    mut file: impl BufRead,
+    empty: Receiver<StringPool>,
+    full: Sender<StringPool>,
+) -> Result<()> {
+    for mut lines in empty {
+        fill(&mut lines, &mut file)?;
+        if lines.is_empty() || full.send(lines).is_err() {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+fn fill(lines: &mut StringPool, reader: &mut impl BufRead) -> Result<()> {
+    while let Some(mut line) = lines.take_dead() {
+        let size = reader.read_until(b'\n', &mut line);
+        if !matches!(size, Ok(n) if n > 0) {
+            line.stay_dead();
+        }
+        if size? == 0 {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+fn run_single_thread<T: Tokenizer + Clone + Send + Sync>(
+    tx: Sender<Result<Vec<KeyedCluster>>>,
+    mut clusterer: Clusterer<T>,
+    read_chunk_size: &ChunkSize,
     file: Arc<Mutex<impl BufRead>>,
+    decoder: LineDecoder,
     progress: ProgressBar,
-    split_regex: Regex,
+    snapshots: Option<(usize, Snapshots)>,
 ) {
-    let mut clusterer = Clusterer::new(options, split_regex);
+    let result = process_chunks(
+        &mut clusterer,
+        read_chunk_size,
+        &file,
+        &decoder,
+        &progress,
+        &snapshots,
+    );
 
-    let mut lines = StringPool::with_capacity(read_chunk_size);
+    // a worker which failed still finishes, so that snapshots don't wait for
+    // it forever
+    if let Some((worker, snapshots)) = &snapshots {
+        snapshots.publish(*worker, &clusterer, true);
+    }
+
+    // the receiver outlives every worker, so this can't fail
+    let _ = tx.send(result.map(|()| clusterer.into_keyed_clusters()));
+}
+
+/// Feed chunks of lines from `file` into `clusterer` until the end of the file
+fn process_chunks<T: Tokenizer>(
+    clusterer: &mut Clusterer<T>,
+    read_chunk_size: &ChunkSize,
+    file: &Mutex<impl BufRead>,
+    decoder: &LineDecoder,
+    progress: &ProgressBar,
+    snapshots: &Option<(usize, Snapshots)>,
+) -> Result<()> {
+    let mut snapshots_answered = 0;
+
+    let mut sizer = ChunkSizer::new(read_chunk_size);
+    let mut lines = StringPool::with_capacity(sizer.size());
 
-    'outer: loop {
+    let mut finished = false;
+    while !finished {
+        let mut measured = ChunkMeasurement::default();
+
+        let start = Instant::now();
         let mut lock = file.lock();
+        measured.lock_wait = start.elapsed();
 
-        fill(&mut lines, &mut *lock);
+        fill(&mut lines, &mut *lock)?;
         drop(lock);
         if lines.is_empty() {
             break;
@@ -89,62 +673,196 @@ fn run_single_thread(
                 (lines.capacity() / LOCK_STEAL_ATTEMPTS).min(lines.len())
             };
 
+            let start = Instant::now();
             let mut size = 0;
             for _ in 0..range_max {
                 let line = lines.take_live().unwrap();
-                clusterer.process_line(line.as_ref());
+                if let Some(line) = decoder.decode(&line)? {
+                    clusterer.process(&line);
+                }
                 size += line.len();
             }
             progress.inc(size as u64);
 
+            measured.processing += start.elapsed();
+            measured.lines += range_max;
+            measured.bytes += size;
+
+            if let Some((worker, snapshots)) = &snapshots {
+                if snapshots.is_requested(snapshots_answered) {
+                    snapshots_answered = snapshots.publish(*worker, clusterer, false);
+                }
+            }
+
             if let Some(mut lock) = file.try_lock() {
-                fill(&mut lines, &mut *lock);
+                fill(&mut lines, &mut *lock)?;
                 if lines.is_empty() {
-                    break 'outer;
+                    finished = true;
+                    break;
                 }
             }
         }
+
+        let size = sizer.size();
+        if sizer.update(measured) != size {
+            lines.set_capacity(sizer.size());
+        }
     }
 
-    tx.send(clusterer.take_result().collect()).unwrap();
+    Ok(())
+}
+
+/// Merge the clusters found by each thread, keeping only those which meet
+/// `min_members` once merged. Neighbouring results are merged in pairs, then
+/// pairs of those, and so on. If `parallel`, merges at the same level of the
+/// tree run in parallel in the current rayon pool, otherwise everything runs
+/// on the calling thread. The result only depends on the order of `results`.
+/// Clusters are compared by their symbols, so every result must come from
+/// clusterers sharing one symbol table.
+fn merge_all(
+    mut results: Vec<Vec<KeyedCluster>>,
+    options: ClustererOptions,
+    parallel: bool,
+) -> Vec<Cluster<'static>> {
+    let mut total = reduce(&mut results, options, parallel);
+
+    total.retain(|(c, _)| c.count >= options.min_members);
+    total.into_iter().map(|(c, _)| c).collect()
 }
 
+fn reduce(
+    results: &mut [Vec<KeyedCluster>],
+    options: ClustererOptions,
+    parallel: bool,
+) -> Vec<KeyedCluster> {
+    match results.len() {
+        0 => Vec::new(),
+        1 => std::mem::take(&mut results[0]),
+        len => {
+            let (left, right) = results.split_at_mut(len / 2);
+            let (mut total, right) = if parallel {
+                rayon::join(
+                    || reduce(left, options, parallel),
+                    || reduce(right, options, parallel),
+                )
+            } else {
+                (
+                    reduce(left, options, parallel),
+                    reduce(right, options, parallel),
+                )
+            };
+
+            merge(&mut total, right, options, parallel);
+            total
+        }
+    }
+}
+
+/// Fold each of `thread_results` into the first cluster of `total` it is
+/// close enough to, or add it to the end of `total` if there is none. This
+/// matches clusters the same way `process_chunk_in_order` matches lines.
 fn merge(
-    total: &mut Vec<Cluster<'static>>,
-    thread_results: Vec<Cluster<'static>>,
+    total: &mut Vec<KeyedCluster>,
+    thread_results: Vec<KeyedCluster>,
     options: ClustererOptions,
+    parallel: bool,
 ) {
-    for mut cluster_a in thread_results {
-        for cluster_b in total.iter_mut() {
-            let score = scoring::distance(
-                &cluster_a.representative,
-                &cluster_b.representative,
-                options.max_dist,
-            );
+    let mut index = LengthIndex::new(total.iter().map(|(_, keys)| keys.representative.len()));
+    let known = total.len();
 
-            if score <= options.max_dist {
-                cluster_b.count += cluster_a.count;
+    let find_known = |c| find_merge_target(total, &index, c, options, 0..known);
+    let matched: Vec<Option<usize>> = if parallel {
+        thread_results.par_iter().map(find_known).collect()
+    } else {
+        thread_results.iter().map(find_known).collect()
+    };
 
-                let pattern_b = std::mem::take(&mut cluster_b.pattern);
+    // clusters of `thread_results` to fold into each cluster of `total`, in
+    // order
+    let mut members: Vec<Vec<KeyedCluster>> = Vec::new();
+    for (cluster_a, id) in thread_results.into_iter().zip(matched) {
+        let id = id
+            .or_else(|| find_merge_target(total, &index, &cluster_a, options, known..total.len()));
 
-                cluster_b.pattern = cluster_a.pattern.merge(pattern_b);
-                break;
+        match id {
+            Some(id) => {
+                if members.len() <= id {
+                    members.resize_with(id + 1, Vec::new);
+                }
+                members[id].push(cluster_a);
+            }
+            None => {
+                index.insert(cluster_a.1.representative.len(), total.len());
+                total.push(cluster_a);
             }
         }
+    }
+
+    let fold = |((cluster_b, keys_b), members): (&mut KeyedCluster, Vec<KeyedCluster>)| {
+        for (mut cluster_a, keys_a) in members {
+            cluster_b.count += cluster_a.count;
+            cluster_b.merge_dimensions(&cluster_a.dimensions);
+
+            let pattern_b = std::mem::take(&mut cluster_b.pattern);
 
-        total.push(cluster_a);
+            let (pattern, keys) =
+                cluster_a
+                    .pattern
+                    .merge_keyed(&keys_a.pattern, pattern_b, &keys_b.pattern);
+            cluster_b.pattern = pattern;
+            keys_b.pattern = keys;
+        }
+    };
+    let targets = &mut total[..members.len()];
+    if parallel {
+        targets.par_iter_mut().zip(members).for_each(fold);
+    } else {
+        targets.iter_mut().zip(members).for_each(fold);
     }
 }
 
+/// Id among `ids` of the first cluster of `total` which `cluster` can be
+/// folded into
+fn find_merge_target(
+    total: &[KeyedCluster],
+    index: &LengthIndex,
+    (_, keys): &KeyedCluster,
+    options: ClustererOptions,
+    ids: Range<usize>,
+) -> Option<usize> {
+    let representative = &keys.representative;
+    let max_dist = options.max_dist;
+
+    index.find(representative.len(), max_dist, ids, |&id| {
+        scoring::distance_of(&total[id].1.representative, representative, max_dist) <= max_dist
+    })
+}
+
 #[cfg(test)]
 mod tests {
-    use std::{fs::File, io::BufReader};
+    use std::{
+        fs::{self, File},
+        io::BufReader,
+        sync::Arc,
+    };
 
     use indicatif::{ProgressBar, ProgressDrawTarget};
     use rayon::ThreadPoolBuilder;
     use regex::Regex;
 
-    use super::run;
+    use crate::{
+        chunk_size::{AdaptiveChunkSize, ChunkSize},
+        clusterer::{Cluster, Clusterer, ClustererOptions},
+        input::{InvalidUtf8, LineDecoder},
+        main_single_core,
+        symbols::Symbols,
+        Error,
+    };
+
+    use super::{
+        merge_all, partition, run, run_deterministic, run_mapped, run_pipelined,
+        run_with_snapshots, Snapshots,
+    };
 
     #[test]
     fn test_file_c_completes() {
@@ -156,9 +874,479 @@ mod tests {
             Default::default(),
             2,
             f,
+            &LineDecoder::default(),
             progress,
             Regex::new("\\s+").unwrap(),
             ThreadPoolBuilder::new().num_threads(1).build().unwrap(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_invalid_utf8() {
+        let lines = b"a b 1\na \xff 2\na b 3\n".repeat(10);
+        let run_with = |policy| {
+            let progress = ProgressBar::new(0);
+            progress.set_draw_target(ProgressDrawTarget::hidden());
+            let decoder = LineDecoder::new(policy);
+
+            let result = run(
+                ClustererOptions::default().with_max_dist(0.5),
+                2,
+                lines.as_slice(),
+                &decoder,
+                progress,
+                Regex::new("\\s+").unwrap(),
+                ThreadPoolBuilder::new().num_threads(2).build().unwrap(),
+            );
+            (result, decoder.invalid_lines())
+        };
+
+        let (result, invalid_lines) = run_with(InvalidUtf8::Skip);
+        let total: u32 = result.unwrap().iter().map(|c| c.count).sum();
+        assert_eq!((total, invalid_lines), (20, 10));
+
+        let (result, _) = run_with(InvalidUtf8::Lossy);
+        let total: u32 = result.unwrap().iter().map(|c| c.count).sum();
+        assert_eq!(total, 30);
+
+        let (result, _) = run_with(InvalidUtf8::Fail);
+        assert!(matches!(result, Err(Error::InvalidUtf8)));
+    }
+
+    #[test]
+    fn test_bytes() {
+        // both invalid bytes are shown as U+FFFD, but are clustered apart
+        let lines = b"a \xff 1\na \xfe 2\na \xff 3\n".repeat(10);
+        let options = ClustererOptions::default().with_max_dist(0.5);
+        let decoder = LineDecoder::bytes();
+        let tokenizer = Regex::new("\\s+").unwrap();
+        let pool = || ThreadPoolBuilder::new().num_threads(2).build().unwrap();
+
+        let summary = |clusters: Vec<Cluster>| {
+            let mut summary: Vec<_> = clusters
+                .iter()
+                .map(|c| (c.pattern.to_string(), c.count))
+                .collect();
+            summary.sort();
+            summary
+        };
+        let expected = vec![
+            ("a \u{fffd} ---".to_string(), 20),
+            ("a \u{fffd} 2".to_string(), 10),
+        ];
+
+        let single = main_single_core(
+            options,
+            lines.as_slice(),
+            &decoder,
+            ProgressBar::hidden(),
+            tokenizer.clone(),
+        );
+        assert_eq!(summary(single.unwrap()), expected);
+
+        let parallel = run(
+            options,
+            2,
+            lines.as_slice(),
+            &decoder,
+            ProgressBar::hidden(),
+            tokenizer.clone(),
+            pool(),
+        );
+        assert_eq!(summary(parallel.unwrap()), expected);
+
+        let pipelined = run_pipelined(
+            options,
+            2,
+            lines.as_slice(),
+            &decoder,
+            ProgressBar::hidden(),
+            tokenizer.clone(),
+            pool(),
+        );
+        assert_eq!(summary(pipelined.unwrap()), expected);
+
+        let deterministic = run_deterministic(
+            options,
+            2,
+            lines.as_slice(),
+            &decoder,
+            ProgressBar::hidden(),
+            tokenizer,
+            pool(),
+        );
+        assert_eq!(summary(deterministic.unwrap()), expected);
+    }
+
+    #[test]
+    fn test_snapshots() {
+        let lines = "a b 1\na b 2\nc d 1\n".repeat(100);
+        let progress = ProgressBar::new(0);
+        progress.set_draw_target(ProgressDrawTarget::hidden());
+        let snapshots = Snapshots::default();
+
+        assert!(snapshots.snapshot().is_empty());
+
+        let clusters = run_with_snapshots(
+            ClustererOptions::default().with_max_dist(0.5),
+            2,
+            lines.as_bytes(),
+            &LineDecoder::default(),
+            progress,
+            Regex::new("\\s+").unwrap(),
+            ThreadPoolBuilder::new().num_threads(2).build().unwrap(),
+            &snapshots,
+        )
+        .unwrap();
+
+        let total: u32 = clusters.iter().map(|c| c.count).sum();
+        let snapshot = snapshots.snapshot();
+        let snapshot_total: u32 = snapshot.iter().map(|c| c.count).sum();
+
+        assert_eq!(snapshot_total, total);
+        assert!(snapshot.windows(2).all(|w| w[0].count >= w[1].count));
+    }
+
+    #[test]
+    fn test_adaptive_chunk_size() {
+        let lines = generate_lines(5, 3000);
+        let adaptive = AdaptiveChunkSize::new(10, 200);
+
+        let clusters = run(
+            ClustererOptions::default().with_max_dist(0.5),
+            ChunkSize::Adaptive(adaptive.clone()),
+            lines.as_bytes(),
+            &LineDecoder::default(),
+            ProgressBar::hidden(),
+            Regex::new("\\s+").unwrap(),
+            ThreadPoolBuilder::new().num_threads(3).build().unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(clusters.iter().map(|c| c.count).sum::<u32>(), 3000);
+
+        let stats = adaptive.stats();
+        assert!(stats.chunks > 0);
+        assert!(stats.smallest >= 10 && stats.largest <= 200);
+    }
+
+    #[test]
+    fn test_partition() {
+        let data = b"a\nbb\nccc\n\nd";
+
+        for parts in 1..8 {
+            let ranges = partition(data, parts);
+
+            assert!(ranges.len() <= parts);
+            assert_eq!(ranges.first().unwrap().start, 0);
+            assert_eq!(ranges.last().unwrap().end, data.len());
+            for pair in ranges.windows(2) {
+                assert_eq!(pair[0].end, pair[1].start);
+                assert_eq!(data[pair[0].end - 1], b'\n');
+            }
+        }
+
+        assert!(partition(b"", 4).is_empty());
+    }
+
+    #[test]
+    fn test_mapped_single_thread_matches_single_core() {
+        let options = ClustererOptions::default().with_max_dist(0.6);
+        let tokenizer = Regex::new("\\s+").unwrap();
+
+        let mapped = run_mapped(
+            options,
+            &File::open("test_files/c.txt").unwrap(),
+            &LineDecoder::default(),
+            ProgressBar::hidden(),
+            tokenizer.clone(),
+            ThreadPoolBuilder::new().num_threads(1).build().unwrap(),
+        )
+        .unwrap();
+        let single = main_single_core(
+            options,
+            BufReader::new(File::open("test_files/c.txt").unwrap()),
+            &LineDecoder::default(),
+            ProgressBar::hidden(),
+            tokenizer,
+        )
+        .unwrap();
+
+        let counts = |clusters: &[Cluster]| clusters.iter().map(|c| c.count).collect::<Vec<_>>();
+        assert_eq!(counts(&mapped), counts(&single));
+    }
+
+    #[test]
+    fn test_mapped_ranges_match_single_core() {
+        const TEMPLATES: &[&str] = &[
+            "user # logged in",
+            "disk # is almost full",
+            "connection to # refused by peer",
+            "cache miss for #",
+        ];
+        let lines: String = (0..2000)
+            .map(|i| TEMPLATES[i % TEMPLATES.len()].replace('#', &i.to_string()) + "\n")
+            .collect();
+        let options = ClustererOptions::default().with_max_dist(0.5);
+        let tokenizer = Regex::new("\\s+").unwrap();
+
+        let path = std::env::temp_dir().join(format!("logmine-ranges-{}", std::process::id()));
+        fs::write(&path, &lines).unwrap();
+        let mapped = run_mapped(
+            options,
+            &File::open(&path).unwrap(),
+            &LineDecoder::default(),
+            ProgressBar::hidden(),
+            tokenizer.clone(),
+            ThreadPoolBuilder::new().num_threads(4).build().unwrap(),
+        )
+        .unwrap();
+        fs::remove_file(&path).unwrap();
+        let single = main_single_core(
+            options,
+            lines.as_bytes(),
+            &LineDecoder::default(),
+            ProgressBar::hidden(),
+            tokenizer,
+        )
+        .unwrap();
+
+        // every thread finds each template, and the merged clusters are the
+        // same as those of a single pass over the file
+        let summary = |clusters: &[Cluster]| {
+            clusters
+                .iter()
+                .map(|c| (c.pattern.to_string(), c.count))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(summary(&mapped), summary(&single));
+        assert_eq!(mapped.len(), TEMPLATES.len());
+    }
+
+    #[test]
+    fn test_mapped_empty_file() {
+        let path = std::env::temp_dir().join(format!("logmine-empty-{}", std::process::id()));
+        File::create(&path).unwrap();
+
+        let clusters = run_mapped(
+            ClustererOptions::default(),
+            &File::open(&path).unwrap(),
+            &LineDecoder::default(),
+            ProgressBar::hidden(),
+            Regex::new("\\s+").unwrap(),
+            ThreadPoolBuilder::new().num_threads(2).build().unwrap(),
+        )
+        .unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert!(clusters.is_empty());
+    }
+
+    /// Log-like lines from a few templates with random fields, the same for
+    /// each `seed`
+    fn generate_lines(seed: u64, count: usize) -> String {
+        const TEMPLATES: &[&str] = &[
+            "INFO request # from # took # ms",
+            "WARN disk # is # full",
+            "ERROR connection to # refused: #",
+            "INFO user # logged in",
+            "DEBUG cache # hit # miss #",
+        ];
+        const WORDS: &[&str] = &[
+            "alpha", "beta", "gamma", "delta", "10.0.0.1", "eu-west", "42",
+        ];
+
+        let mut state = seed;
+        let mut next = move |n: usize| {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (state >> 33) as usize % n
+        };
+
+        let mut text = String::new();
+        for _ in 0..count {
+            let template = TEMPLATES[next(TEMPLATES.len())];
+            for (i, part) in template.split('#').enumerate() {
+                if i > 0 {
+                    match next(3) {
+                        0 => text.push_str(&next(1000).to_string()),
+                        1 => text.push_str(WORDS[next(WORDS.len())]),
+                        _ => text.push_str(&format!("{} {}", next(10), WORDS[next(WORDS.len())])),
+                    }
+                }
+                text.push_str(part);
+            }
+            text.push('\n');
+        }
+        text
+    }
+
+    #[test]
+    fn test_deterministic_matches_single_core() {
+        let tokenizer = Regex::new("\\s+").unwrap();
+
+        for seed in 0..4 {
+            let lines = generate_lines(seed, 500);
+
+            for (max_dist, min_members) in [(0.2, 1), (0.5, 3), (0.8, 2)] {
+                let options = ClustererOptions::default()
+                    .with_max_dist(max_dist)
+                    .with_min_members(min_members);
+
+                let expected = main_single_core(
+                    options,
+                    lines.as_bytes(),
+                    &LineDecoder::default(),
+                    ProgressBar::hidden(),
+                    tokenizer.clone(),
+                )
+                .unwrap();
+
+                for (jobs, chunk_size) in [(1, 1000), (2, 1), (3, 7), (8, 64)] {
+                    let clusters = run_deterministic(
+                        options,
+                        chunk_size,
+                        lines.as_bytes(),
+                        &LineDecoder::default(),
+                        ProgressBar::hidden(),
+                        tokenizer.clone(),
+                        ThreadPoolBuilder::new().num_threads(jobs).build().unwrap(),
+                    )
+                    .unwrap();
+
+                    assert_eq!(
+                        clusters, expected,
+                        "seed {}, max_dist {}, jobs {}, chunk size {}",
+                        seed, max_dist, jobs, chunk_size
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_merge_all() {
+        let options = ClustererOptions::default()
+            .with_max_dist(0.5)
+            .with_min_members(2);
+        let symbols = Arc::new(Symbols::new());
+        let thread_results = |lines: &[&str]| {
+            let mut clusterer =
+                Clusterer::new(options, Regex::new("\\s+").unwrap()).with_symbols(symbols.clone());
+            for line in lines {
+                clusterer.process_line(line);
+            }
+            clusterer.into_keyed_clusters()
+        };
+
+        let clusters = merge_all(
+            vec![
+                thread_results(&["a b 1", "x y z"]),
+                thread_results(&["a b 2", "p q r"]),
+            ],
+            options,
+            true,
+        );
+
+        // a cluster merged into another is not also kept on its own, and
+        // min_members applies to the merged counts
+        assert_eq!(clusters.len(), 1);
+        assert_eq!(clusters[0].count, 2);
+        assert_eq!(clusters[0].pattern.to_string(), "a b ---");
+    }
+
+    #[test]
+    fn test_merge_all_tree() {
+        let options = ClustererOptions::default().with_max_dist(0.5);
+        let pool = ThreadPoolBuilder::new().num_threads(3).build().unwrap();
+
+        let symbols = Arc::new(Symbols::new());
+        let results = (0..7)
+            .map(|i| {
+                let mut clusterer = Clusterer::new(options, Regex::new("\\s+").unwrap())
+                    .with_symbols(symbols.clone());
+                clusterer.process_line(&format!("a b {}", i));
+                clusterer.process_line(&format!("only in {} {} {}", i, i, i));
+                clusterer.into_keyed_clusters()
+            })
+            .collect::<Vec<_>>();
+
+        let clusters = pool.install(|| merge_all(results.clone(), options, true));
+        // snapshots merge on one thread, which must give the same result
+        assert_eq!(merge_all(results, options, false), clusters);
+
+        assert_eq!(clusters.len(), 8);
+        assert_eq!(clusters[0].count, 7);
+        assert_eq!(clusters[0].pattern.to_string(), "a b ---");
+        assert!(clusters[1..].iter().all(|c| c.count == 1));
+    }
+
+    #[test]
+    fn test_parallel_counts_every_line_once() {
+        let lines = generate_lines(7, 2000);
+        let options = ClustererOptions::default().with_max_dist(0.5);
+        let pool = || ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+
+        let streamed = run(
+            options,
+            50,
+            lines.as_bytes(),
+            &LineDecoder::default(),
+            ProgressBar::hidden(),
+            Regex::new("\\s+").unwrap(),
+            pool(),
+        )
+        .unwrap();
+
+        let path = std::env::temp_dir().join(format!("logmine-lines-{}", std::process::id()));
+        fs::write(&path, &lines).unwrap();
+        let mapped = run_mapped(
+            options,
+            &File::open(&path).unwrap(),
+            &LineDecoder::default(),
+            ProgressBar::hidden(),
+            Regex::new("\\s+").unwrap(),
+            pool(),
+        )
+        .unwrap();
+        fs::remove_file(&path).unwrap();
+
+        for clusters in [streamed, mapped] {
+            assert_eq!(clusters.iter().map(|c| c.count).sum::<u32>(), 2000);
+        }
+    }
+
+    #[test]
+    fn test_pipelined() {
+        let lines = generate_lines(3, 1000);
+        let tokenizer = Regex::new("\\s+").unwrap();
+
+        for (jobs, chunk_size) in [(1, 1), (2, 10), (4, 1000)] {
+            let clusters = run_pipelined(
+                ClustererOptions::default().with_max_dist(0.5),
+                chunk_size,
+                lines.as_bytes(),
+                &LineDecoder::default(),
+                ProgressBar::hidden(),
+                tokenizer.clone(),
+                ThreadPoolBuilder::new().num_threads(jobs).build().unwrap(),
+            )
+            .unwrap();
+
+            assert_eq!(clusters.iter().map(|c| c.count).sum::<u32>(), 1000);
+        }
+
+        let result = run_pipelined(
+            ClustererOptions::default(),
+            2,
+            &b"a\nb\n\xff\nc\n"[..],
+            &LineDecoder::new(InvalidUtf8::Fail),
+            ProgressBar::hidden(),
+            tokenizer,
+            ThreadPoolBuilder::new().num_threads(2).build().unwrap(),
         );
+        assert!(matches!(result, Err(Error::InvalidUtf8)));
     }
 }
@@ -1,13 +1,35 @@
-use std::borrow::Cow;
+use std::{
+    borrow::{Borrow, Cow},
+    fmt,
+    hash::{Hash, Hasher},
+    ops::Range,
+    sync::Arc,
+};
 
+use regex::Regex;
 use seal::pair::{AlignmentSet, InMemoryAlignmentMatrix, SmithWaterman, Step};
 
+use crate::{symbols::Symbol, tokenizer::Tokenizer};
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum PatternElement<'a> {
-    Text(Cow<'a, str>),
+    Text(Text<'a>),
     Placeholder,
 }
 
+/// Text of a `PatternElement`, either borrowed, such as from the line a
+/// pattern was split from, or shared, such as with a `Symbols` table so that
+/// clusters with the same tokens don't each keep a copy of them. Text may also
+/// be raw bytes, which needn't be valid UTF-8 (see `as_str`). Compares by the
+/// bytes of the text.
+#[derive(Debug, Clone)]
+pub enum Text<'a> {
+    Borrowed(&'a str),
+    Shared(Arc<str>),
+    BorrowedBytes(&'a [u8]),
+    SharedBytes(Arc<[u8]>),
+}
+
 #[cfg(feature = "small-vec")]
 type Storage<'a> = smallvec::SmallVec<[PatternElement<'a>; 5]>;
 #[cfg(not(feature = "small-vec"))]
@@ -16,22 +38,149 @@ type Storage<'a> = Vec<PatternElement<'a>>;
 #[derive(Debug, PartialEq, Clone, Default)]
 pub struct Pattern<'a> {
     items: Storage<'a>,
+    /// The text consumed by the split regex between each pair of consecutive
+    /// items, or `Placeholder` where the lines of the pattern were split by
+    /// different text. Empty if separators weren't recorded, in which case
+    /// they are shown as single spaces.
+    separators: Storage<'a>,
 }
 
 impl<'a> Pattern<'a> {
     pub fn new(items: Storage<'a>) -> Self {
-        Self { items }
+        Self {
+            items,
+            separators: Default::default(),
+        }
+    }
+
+    /// Pattern with the separators between its items. There must be one less
+    /// separator than there are items.
+    pub fn with_separators(items: Storage<'a>, separators: Storage<'a>) -> Self {
+        assert_eq!(separators.len(), items.len().saturating_sub(1));
+
+        Self { items, separators }
+    }
+
+    /// Split `line` into tokens with `tokenizer`, appending each token along
+    /// with the separators between them to this pattern, which must be empty.
+    /// A trailing line terminator is not part of the pattern.
+    pub fn push_line(&mut self, line: &'a str, tokenizer: &(impl Tokenizer + ?Sized)) -> &mut Self {
+        self.push_line_with_fields(line, tokenizer, &mut |_, _| {})
+    }
+
+    /// Same as `push_line`, also calling `on_field` with the fields of `line`
+    /// as `Tokenizer::tokenize_with_fields` does
+    pub fn push_line_with_fields(
+        &mut self,
+        line: &'a str,
+        tokenizer: &(impl Tokenizer + ?Sized),
+        on_field: &mut dyn FnMut(usize, Range<usize>),
+    ) -> &mut Self {
+        debug_assert!(self.is_empty());
+
+        let line = strip_line_terminator(line);
+
+        let mut previous_end = None;
+        tokenizer.tokenize_with_fields(
+            line,
+            &mut |token| {
+                if let Some(end) = previous_end {
+                    self.separators.push(PatternElement::Text(Text::Borrowed(
+                        &line[end..token.start],
+                    )));
+                }
+                self.items
+                    .push(PatternElement::Text(Text::Borrowed(&line[token.clone()])));
+                previous_end = Some(token.end);
+            },
+            on_field,
+        );
+
+        self
+    }
+
+    /// Same as `push_line`, for a line of raw bytes which may not be UTF-8,
+    /// split with `Tokenizer::tokenize_bytes`
+    pub fn push_bytes(
+        &mut self,
+        line: &'a [u8],
+        tokenizer: &(impl Tokenizer + ?Sized),
+    ) -> &mut Self {
+        debug_assert!(self.is_empty());
+
+        let line = line.strip_suffix(b"\n").unwrap_or(line);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+
+        let mut previous_end = None;
+        tokenizer.tokenize_bytes(line, &mut |token| {
+            if let Some(end) = previous_end {
+                self.separators
+                    .push(PatternElement::Text(Text::BorrowedBytes(
+                        &line[end..token.start],
+                    )));
+            }
+            self.items.push(PatternElement::Text(Text::BorrowedBytes(
+                &line[token.clone()],
+            )));
+            previous_end = Some(token.end);
+        });
+
+        self
     }
 
     pub fn iter(&self) -> impl Iterator<Item = &PatternElement<'a>> {
         self.items.iter()
     }
 
+    pub(crate) fn items(&self) -> &[PatternElement<'a>] {
+        &self.items
+    }
+
     pub fn drain<'b>(&'b mut self) -> impl 'b + Iterator<Item = PatternElement<'a>> {
+        self.separators.clear();
         self.items.drain(..)
     }
 
-    pub fn push_text(&mut self, item: impl Into<Cow<'a, str>>) -> &mut Self {
+    /// The separators between consecutive items, which is empty if they
+    /// weren't recorded
+    pub fn separators(&self) -> impl Iterator<Item = &PatternElement<'a>> {
+        self.separators.iter()
+    }
+
+    /// The separator between item `i` and item `i + 1`, if separators were
+    /// recorded
+    pub fn separator(&self, i: usize) -> Option<&PatternElement<'a>> {
+        self.separators.get(i)
+    }
+
+    /// How the separator between item `i` and item `i + 1` is shown
+    pub(crate) fn separator_text(&self, i: usize) -> Cow<'_, str> {
+        match self.separators.get(i) {
+            Some(PatternElement::Text(t)) => t.to_str_lossy(),
+            _ => Cow::Borrowed(" "),
+        }
+    }
+
+    fn has_separators(&self) -> bool {
+        self.separators.len() == self.items.len().saturating_sub(1)
+    }
+
+    /// Copy of this pattern which owns all of its text
+    pub fn to_owned_pattern(&self) -> Pattern<'static> {
+        fn owned(element: &PatternElement) -> PatternElement<'static> {
+            match element {
+                PatternElement::Placeholder => PatternElement::Placeholder,
+                PatternElement::Text(t) => PatternElement::Text(t.to_shared()),
+            }
+        }
+
+        Pattern {
+            items: self.items.iter().map(owned).collect(),
+            separators: self.separators.iter().map(owned).collect(),
+        }
+    }
+
+    pub fn push_text(&mut self, item: impl Into<Text<'a>>) -> &mut Self {
         self.items.push(PatternElement::Text(item.into()));
         self
     }
@@ -54,18 +203,200 @@ impl<'a> Pattern<'a> {
     pub fn clear_and_reinterpret<'b>(mut self) -> Pattern<'b> {
         let mut items = std::mem::take(&mut self.items);
         items.clear();
+        let mut separators = std::mem::take(&mut self.separators);
+        separators.clear();
 
         // Safety ----- it is acceptable to re-use vector heap space here since
         // we ensure to clear the vector of any non-'static items before running
         // the transmute.
         let static_items = unsafe { std::mem::transmute::<Storage<'a>, Storage<'b>>(items) };
+        let static_separators =
+            unsafe { std::mem::transmute::<Storage<'a>, Storage<'b>>(separators) };
 
         Pattern {
             items: static_items,
+            separators: static_separators,
+        }
+    }
+}
+
+impl<'a> Pattern<'a> {
+    /// Build an anchored regex matching every line which fits this pattern,
+    /// where `tokenizer` is what the lines were split with.
+    ///
+    /// Text elements must match exactly, and so must recorded separators;
+    /// separators which differed between lines, or weren't recorded, match
+    /// anything the tokenizer may leave between tokens. Each placeholder
+    /// becomes a capture group named `p0`, `p1` and so on, in order, since
+    /// groups in the tokenizer's regexes would shift the numbers of unnamed
+    /// groups. Placeholders may also match nothing at all since placeholders
+    /// produced by `merge` stand in for zero or more tokens.
+    pub fn to_regex(&self, tokenizer: &(impl Tokenizer + ?Sized)) -> Result<Regex, regex::Error> {
+        let any_separator = format!("(?:{})", tokenizer.separator_regex());
+        // the separator between item `i` and item `i + 1`
+        let separator = |i: usize| match self.separators.get(i) {
+            Some(PatternElement::Text(t)) => regex::escape(&t.to_str_lossy()),
+            _ => any_separator.clone(),
+        };
+
+        let ends = match tokenizer.ends_regex() {
+            Some(ends) => format!("(?:{})", ends),
+            None => String::new(),
+        };
+
+        let mut regex = String::from("(?s)^");
+
+        // with nothing to anchor them, the first placeholder takes the whole
+        // line and the rest match nothing
+        if !self
+            .items
+            .iter()
+            .any(|e| matches!(e, PatternElement::Text(_)))
+        {
+            for i in 0..self.items.len() {
+                let value = if i == 0 { ".*" } else { "" };
+                regex.push_str(&format!("(?P<p{}>{})", i, value));
+            }
+            regex.push('$');
+
+            return Regex::new(&regex);
+        }
+
+        regex.push_str(&ends);
+
+        // until the first text element is reached, placeholders take the
+        // separator after them instead of the one before them
+        let mut leading = true;
+        let mut placeholders = 0;
+        for (i, element) in self.iter().enumerate() {
+            match element {
+                PatternElement::Text(t) if leading => {
+                    regex.push_str(&regex::escape(&t.to_str_lossy()));
+                    leading = false;
+                }
+                PatternElement::Text(t) => {
+                    regex.push_str(&separator(i - 1));
+                    regex.push_str(&regex::escape(&t.to_str_lossy()));
+                }
+                PatternElement::Placeholder if leading => {
+                    regex.push_str(&format!("(?:(?P<p{}>.*?){})?", placeholders, separator(i)));
+                    placeholders += 1;
+                }
+                PatternElement::Placeholder => {
+                    regex.push_str(&format!(
+                        "(?:{}(?P<p{}>.*?))?",
+                        separator(i - 1),
+                        placeholders
+                    ));
+                    placeholders += 1;
+                }
+            }
+        }
+
+        regex.push_str(&ends);
+        regex.push('$');
+
+        Regex::new(&regex)
+    }
+}
+
+impl<'a> Pattern<'a> {
+    /// Does `line`, split with `tokenizer`, fit this pattern? Placeholders
+    /// match any number of tokens, including none, since placeholders
+    /// produced by `merge` may stand in for a gap in one of the merged lines.
+    pub fn matches(&self, line: &str, tokenizer: &(impl Tokenizer + ?Sized)) -> bool {
+        self.extract(line, tokenizer).is_some()
+    }
+
+    /// Find the text filling each placeholder of this pattern in `line`, or
+    /// `None` if the line doesn't fit the pattern. Each value is a slice of
+    /// `line` covering the tokens matched by the placeholder and the
+    /// separators between them. Placeholders which match no tokens give an
+    /// empty slice. When there is more than one way to fit the line, earlier
+    /// placeholders take as few tokens as possible.
+    pub fn extract<'l>(
+        &self,
+        line: &'l str,
+        tokenizer: &(impl Tokenizer + ?Sized),
+    ) -> Option<Vec<&'l str>> {
+        let tokens = token_spans(line, tokenizer);
+        let token = |i: usize| &line[tokens[i].0..tokens[i].1];
+
+        // index of the first token matched by each element, plus the end
+        let mut positions = vec![0; self.items.len() + 1];
+        // the latest placeholder seen, and how many tokens it has taken
+        let mut backtrack: Option<(usize, usize)> = None;
+
+        let (mut e, mut t) = (0, 0);
+        while t < tokens.len() {
+            match self.items.get(e) {
+                Some(PatternElement::Text(text)) if text == token(t) => {
+                    positions[e] = t;
+                    e += 1;
+                    t += 1;
+                }
+                Some(PatternElement::Placeholder) => {
+                    positions[e] = t;
+                    backtrack = Some((e, 0));
+                    e += 1;
+                }
+                _ => {
+                    // give the latest placeholder one more token and try
+                    // again from there
+                    let (placeholder, taken) = backtrack?;
+                    backtrack = Some((placeholder, taken + 1));
+                    e = placeholder + 1;
+                    t = positions[placeholder] + taken + 1;
+                }
+            }
+        }
+
+        while let Some(PatternElement::Placeholder) = self.items.get(e) {
+            positions[e] = t;
+            e += 1;
         }
+
+        if e < self.items.len() {
+            return None;
+        }
+        positions[e] = tokens.len();
+
+        let values = self
+            .items
+            .iter()
+            .enumerate()
+            .filter(|(_, element)| **element == PatternElement::Placeholder)
+            .map(|(i, _)| {
+                let (first, end) = (positions[i], positions[i + 1]);
+                if first == end {
+                    let at = if first == 0 { 0 } else { tokens[first - 1].1 };
+                    &line[at..at]
+                } else {
+                    &line[tokens[first].0..tokens[end - 1].1]
+                }
+            })
+            .collect();
+
+        Some(values)
     }
 }
 
+/// `line` without a trailing `\n` or `\r\n`
+pub(crate) fn strip_line_terminator(line: &str) -> &str {
+    let line = line.strip_suffix('\n').unwrap_or(line);
+    line.strip_suffix('\r').unwrap_or(line)
+}
+
+/// Byte ranges of the tokens of `line`, ignoring any line terminator
+fn token_spans(line: &str, tokenizer: &(impl Tokenizer + ?Sized)) -> Vec<(usize, usize)> {
+    let line = strip_line_terminator(line);
+    let mut spans = Vec::new();
+
+    tokenizer.tokenize(line, &mut |token| spans.push((token.start, token.end)));
+
+    spans
+}
+
 impl<'a> IntoIterator for Pattern<'a> {
     type Item = PatternElement<'a>;
     type IntoIter = <Vec<PatternElement<'a>> as IntoIterator>::IntoIter;
@@ -77,8 +408,31 @@ impl<'a> IntoIterator for Pattern<'a> {
 
 impl Pattern<'static> {
     pub fn merge(&mut self, other: Pattern<'_>) -> Pattern<'static> {
+        self.merge_inner(other, None).0
+    }
+
+    /// Same as `merge`, but items are compared by `keys`, the symbols of the
+    /// items of this pattern, and `other_keys`, those of `other`. Also returns
+    /// the symbols of the items of the merged pattern, whose text is taken
+    /// from this pattern.
+    pub(crate) fn merge_keyed(
+        &mut self,
+        keys: &[Symbol],
+        other: Pattern<'_>,
+        other_keys: &[Symbol],
+    ) -> (Pattern<'static>, Vec<Symbol>) {
+        debug_assert_eq!((keys.len(), other_keys.len()), (self.len(), other.len()));
+
+        self.merge_inner(other, Some((keys, other_keys)))
+    }
+
+    fn merge_inner(
+        &mut self,
+        other: Pattern<'_>,
+        keys: Option<(&[Symbol], &[Symbol])>,
+    ) -> (Pattern<'static>, Vec<Symbol>) {
         if self.items.is_empty() && other.items.is_empty() {
-            return Pattern::default();
+            return Default::default();
         }
 
         let strategy = SmithWaterman::new(10, -1, 0, 0);
@@ -87,44 +441,245 @@ impl Pattern<'static> {
             self.items.len(),
             other.items.len(),
             strategy,
-            |p1_idx, p2_idx| self.items[p1_idx] == other.items[p2_idx],
+            |p1_idx, p2_idx| match keys {
+                Some((keys, other_keys)) => keys[p1_idx] == other_keys[p2_idx],
+                None => self.items[p1_idx] == other.items[p2_idx],
+            },
         )
         .unwrap();
 
         let in_pattern = self;
-        let mut out_pattern = other.clear_and_reinterpret::<'static>();
+
+        // the indexes of each input covered by each element of the output
+        let mut spans: Vec<(Covered, Covered)> = Vec::new();
+        let extend = |span: &mut Covered, i: usize| {
+            *span = Some(match *span {
+                Some((first, _)) => (first, i),
+                None => (i, i),
+            });
+        };
+
+        let mut out_items = Storage::new();
+        let mut out_keys = Vec::new();
 
         let mut just_inserted_placeholder = false;
         for s in aligner.global_alignment().steps() {
             match s {
-                Step::Align { x, .. } => {
+                Step::Align { x, y } => {
                     let element =
                         std::mem::replace(&mut in_pattern.items[x], PatternElement::Placeholder);
 
-                    out_pattern.items.push(element);
+                    out_items.push(element);
+                    if let Some((keys, _)) = keys {
+                        out_keys.push(keys[x]);
+                    }
+                    spans.push((Some((x, x)), Some((y, y))));
                     just_inserted_placeholder = false;
                 }
                 Step::Delete { .. } | Step::Insert { .. } => {
                     if !just_inserted_placeholder {
-                        out_pattern.items.push(PatternElement::Placeholder);
+                        out_items.push(PatternElement::Placeholder);
+                        if keys.is_some() {
+                            out_keys.push(Symbol::PLACEHOLDER);
+                        }
+                        spans.push((None, None));
                         just_inserted_placeholder = true;
                     }
+
+                    let span = spans.last_mut().unwrap();
+                    match s {
+                        Step::Delete { x } => extend(&mut span.0, x),
+                        Step::Insert { y } => extend(&mut span.1, y),
+                        Step::Align { .. } => unreachable!(),
+                    }
                 }
             }
         }
 
-        out_pattern
+        let mut out_separators = Storage::new();
+        if in_pattern.has_separators() && other.has_separators() {
+            for pair in spans.windows(2) {
+                let a = between(&in_pattern.separators, pair[0].0, pair[1].0);
+                let b = between(&other.separators, pair[0].1, pair[1].1);
+
+                out_separators.push(match (a, b) {
+                    (Some(a), Some(b)) if a == b => a.clone(),
+                    _ => PatternElement::Placeholder,
+                });
+            }
+        }
+
+        let mut out_pattern = other.clear_and_reinterpret::<'static>();
+        out_pattern.items.extend(out_items);
+        out_pattern.separators.extend(out_separators);
+
+        (out_pattern, out_keys)
+    }
+}
+
+/// The first and last index of one input to `merge` covered by an element of
+/// the output
+type Covered = Option<(usize, usize)>;
+
+/// The separator of one input to `merge` between two consecutive elements of
+/// the output, which covered the indexes `before` and `after` of that input.
+/// An element which covers none of the input, such as a placeholder for a gap
+/// in it, takes the separator on its other side.
+fn between<'s, 'a>(
+    separators: &'s Storage<'a>,
+    before: Covered,
+    after: Covered,
+) -> Option<&'s PatternElement<'a>> {
+    match (before, after) {
+        (Some((_, last)), _) => separators.get(last),
+        (None, Some((first, _))) => separators.get(first.checked_sub(1)?),
+        (None, None) => None,
+    }
+}
+
+impl<'a> fmt::Display for Pattern<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, element) in self.iter().enumerate() {
+            if i > 0 {
+                f.write_str(&self.separator_text(i - 1))?;
+            }
+
+            match element {
+                PatternElement::Text(t) => write!(f, "{}", t)?,
+                PatternElement::Placeholder => write!(f, "---")?,
+            }
+        }
+
+        Ok(())
     }
 }
 
 impl<'a> From<&'a str> for PatternElement<'a> {
     fn from(s: &'a str) -> Self {
-        Self::Text(Cow::Borrowed(s))
+        Self::Text(Text::Borrowed(s))
+    }
+}
+
+impl<'a> Text<'a> {
+    /// This text, copied into an `Arc` unless it is shared already
+    pub fn to_shared(&self) -> Text<'static> {
+        match self {
+            Text::Borrowed(s) => Text::Shared(Arc::from(*s)),
+            Text::Shared(s) => Text::Shared(s.clone()),
+            Text::BorrowedBytes(b) => Text::SharedBytes(Arc::from(*b)),
+            Text::SharedBytes(b) => Text::SharedBytes(b.clone()),
+        }
+    }
+
+    /// The bytes of this text, which are its UTF-8 unless it is raw bytes
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            Text::Borrowed(s) => s.as_bytes(),
+            Text::Shared(s) => s.as_bytes(),
+            Text::BorrowedBytes(b) => b,
+            Text::SharedBytes(b) => b,
+        }
+    }
+
+    /// This text as a `str`, or `None` if it is raw bytes which aren't valid
+    /// UTF-8
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Text::Borrowed(s) => Some(s),
+            Text::Shared(s) => Some(s),
+            Text::BorrowedBytes(b) => std::str::from_utf8(b).ok(),
+            Text::SharedBytes(b) => std::str::from_utf8(b).ok(),
+        }
+    }
+
+    /// This text, with raw bytes decoded as UTF-8 and any invalid sequences
+    /// replaced by U+FFFD
+    pub fn to_str_lossy(&self) -> Cow<'_, str> {
+        match self {
+            Text::Borrowed(s) => Cow::Borrowed(s),
+            Text::Shared(s) => Cow::Borrowed(s),
+            Text::BorrowedBytes(b) => String::from_utf8_lossy(b),
+            Text::SharedBytes(b) => String::from_utf8_lossy(b),
+        }
+    }
+}
+
+impl<'a, 'b> PartialEq<Text<'b>> for Text<'a> {
+    fn eq(&self, other: &Text<'b>) -> bool {
+        self.as_bytes() == other.as_bytes()
+    }
+}
+
+impl<'a> Eq for Text<'a> {}
+
+impl<'a> PartialEq<str> for Text<'a> {
+    fn eq(&self, other: &str) -> bool {
+        self.as_bytes() == other.as_bytes()
+    }
+}
+
+/// Hashes the same as `as_bytes`, so that tables of text can be looked up by
+/// bytes
+impl<'a> Hash for Text<'a> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_bytes().hash(state)
+    }
+}
+
+impl<'a> Borrow<[u8]> for Text<'a> {
+    fn borrow(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl<'a> fmt::Display for Text<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_str_lossy())
+    }
+}
+
+impl<'a> From<&'a str> for Text<'a> {
+    fn from(s: &'a str) -> Self {
+        Text::Borrowed(s)
+    }
+}
+
+impl From<String> for Text<'static> {
+    fn from(s: String) -> Self {
+        Text::Shared(Arc::from(s))
+    }
+}
+
+impl From<Arc<str>> for Text<'static> {
+    fn from(s: Arc<str>) -> Self {
+        Text::Shared(s)
+    }
+}
+
+impl<'a> From<&'a [u8]> for Text<'a> {
+    fn from(b: &'a [u8]) -> Self {
+        Text::BorrowedBytes(b)
+    }
+}
+
+impl<'a> From<Cow<'a, str>> for Text<'a> {
+    fn from(s: Cow<'a, str>) -> Self {
+        match s {
+            Cow::Borrowed(s) => Text::Borrowed(s),
+            Cow::Owned(s) => s.into(),
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use regex::Regex;
+
+    use crate::{
+        clusterer::{Clusterer, ClustererOptions},
+        tokenizer::{RegexFind, Tokenizer},
+    };
+
     use super::{Pattern, PatternElement};
 
     #[test]
@@ -178,4 +733,234 @@ mod tests {
             ]),
         );
     }
+
+    fn merge_lines(tokenizer: &impl Tokenizer, lines: &[&str]) -> Pattern<'static> {
+        let mut pattern: Option<Pattern<'static>> = None;
+        for line in lines {
+            let mut line_pattern = Pattern::default();
+            line_pattern.push_line(line, tokenizer);
+
+            pattern = Some(match pattern {
+                Some(mut pattern) => pattern.merge(line_pattern),
+                None => line_pattern.to_owned_pattern(),
+            });
+        }
+
+        pattern.unwrap()
+    }
+
+    #[test]
+    fn test_push_line_keeps_separators() {
+        let mut pattern = Pattern::default();
+        pattern.push_line("a=1,b=2\r\n", &Regex::new("[=,]").unwrap());
+
+        assert_eq!(
+            pattern,
+            Pattern::with_separators(vec_into!["a", "1", "b", "2"], vec_into!["=", ",", "="])
+        );
+        assert_eq!(pattern.to_string(), "a=1,b=2");
+    }
+
+    #[test]
+    fn test_merge_keeps_equal_separators() {
+        let split_regex = Regex::new("[=,;]").unwrap();
+
+        let pattern = merge_lines(&split_regex, &["a=1,b=2", "a=3,b=2"]);
+        assert_eq!(pattern.to_string(), "a=---,b=2");
+        assert_eq!(
+            pattern.to_regex(&split_regex).unwrap().as_str(),
+            "(?s)^a(?:=(?P<p0>.*?))?,b=2$"
+        );
+
+        // separators which differ between lines are shown as spaces, and may
+        // be anything the split regex matches
+        let pattern = merge_lines(&split_regex, &["a=1,b=2", "a=1;b=2"]);
+        assert_eq!(pattern.to_string(), "a=1 b=2");
+        assert_eq!(
+            pattern.to_regex(&split_regex).unwrap().as_str(),
+            "(?s)^a=1(?:[=,;])b=2$"
+        );
+    }
+
+    #[test]
+    fn test_merge_separators_around_gaps() {
+        let split_regex = Regex::new("\\s+").unwrap();
+        let lines = ["a b  c", "a c"];
+
+        let pattern = merge_lines(&split_regex, &lines);
+        assert_eq!(
+            pattern,
+            Pattern::with_separators(
+                vec_into!["a", PatternElement::Placeholder, "c"],
+                vec_into![" ", PatternElement::Placeholder],
+            )
+        );
+        assert_regex_matches(&pattern, &split_regex, &lines);
+    }
+
+    #[test]
+    fn test_regex_with_find_tokenizer() {
+        let tokenizer = RegexFind::new(Regex::new("\\w+").unwrap());
+        let lines = ["[a] x=1;", "[a] x=22;"];
+
+        let pattern = merge_lines(&tokenizer, &lines);
+        assert_eq!(pattern.to_string(), "a] x=---");
+        assert_regex_matches(&pattern, &tokenizer, &lines);
+    }
+
+    fn assert_regex_matches_cluster(split_pattern: &str, lines: &[&str]) {
+        let split_regex = Regex::new(split_pattern).unwrap();
+        let mut clusterer = Clusterer::new(
+            ClustererOptions::default().with_max_dist(0.7),
+            split_regex.clone(),
+        );
+        for line in lines {
+            clusterer.process_line(line);
+        }
+
+        let clusters: Vec<_> = clusterer.take_result().collect();
+        assert_eq!(clusters.len(), 1, "{:?}", clusters);
+
+        assert_regex_matches(&clusters[0].pattern, &split_regex, lines);
+    }
+
+    /// Merge `lines` into one pattern directly, for lines whose tokens don't
+    /// line up well enough to be put in one cluster
+    fn assert_regex_matches_merged(split_pattern: &str, lines: &[&str]) {
+        let split_regex = Regex::new(split_pattern).unwrap();
+
+        assert_regex_matches(&merge_lines(&split_regex, lines), &split_regex, lines);
+    }
+
+    fn assert_regex_matches(pattern: &Pattern, tokenizer: &impl Tokenizer, lines: &[&str]) {
+        let regex = pattern.to_regex(tokenizer).unwrap();
+        for line in lines {
+            assert!(regex.is_match(line), "{} does not match {:?}", regex, line);
+        }
+    }
+
+    #[test]
+    fn test_regex_matches_every_line_in_cluster() {
+        assert_regex_matches_cluster(
+            "\\s+",
+            &[
+                "user 12 logged in from 10.0.0.1\n",
+                "user 13 logged in from 10.0.0.2\n",
+                "user 14 logged  in\n",
+                "user 15 logged in from (somewhere) 10.0.0.3\n",
+            ],
+        );
+    }
+
+    #[test]
+    fn test_regex_with_leading_placeholder() {
+        assert_regex_matches_merged(",", &["a,x,y,z", "b,x,y,z", "x,y,z", "c,d,x,y,z"]);
+    }
+
+    #[test]
+    fn test_regex_escapes_and_captures() {
+        let pattern = Pattern::new(vec_into![
+            "[error]",
+            PatternElement::Placeholder,
+            "a.b",
+            PatternElement::Placeholder,
+        ]);
+        let regex = pattern.to_regex(&Regex::new("\\s+").unwrap()).unwrap();
+
+        let captures = regex.captures("[error] 1 2 a.b 3").unwrap();
+        assert_eq!(&captures["p0"], "1 2");
+        assert_eq!(&captures["p1"], "3");
+
+        assert!(regex.is_match("[error] a.b"));
+        assert!(!regex.is_match("[error] 1 axb 3"));
+        assert!(!regex.is_match("x [error] 1 a.b 3"));
+
+        let only_placeholders = Pattern::new(vec_into![PatternElement::Placeholder]);
+        let regex = only_placeholders
+            .to_regex(&Regex::new("\\s+").unwrap())
+            .unwrap();
+        assert_eq!(&regex.captures("a b").unwrap()["p0"], "a b");
+    }
+
+    #[test]
+    fn test_regex_with_groups_in_separator() {
+        let split_regex = Regex::new("(,|;)").unwrap();
+        let lines = ["a,1;x", "a;2;x", "a,3,x"];
+
+        let pattern = merge_lines(&split_regex, &lines);
+        assert_eq!(pattern.to_string(), "a --- x");
+        assert_regex_matches(&pattern, &split_regex, &lines);
+
+        // the groups of the separators don't take the place of placeholders
+        let regex = pattern.to_regex(&split_regex).unwrap();
+        assert_eq!(&regex.captures("a;22,x").unwrap()["p0"], "22");
+    }
+
+    #[test]
+    fn test_extract() {
+        let split_regex = Regex::new("\\s+").unwrap();
+        let pattern = Pattern::new(vec_into![
+            "a",
+            PatternElement::Placeholder,
+            "b",
+            PatternElement::Placeholder,
+        ]);
+
+        assert_eq!(
+            pattern.extract("a x  y b z", &split_regex),
+            Some(vec!["x  y", "z"])
+        );
+        assert_eq!(pattern.extract("a b", &split_regex), Some(vec!["", ""]));
+        assert_eq!(
+            pattern.extract("a b x b y", &split_regex),
+            Some(vec!["", "x b y"])
+        );
+        assert_eq!(pattern.extract("a x y", &split_regex), None);
+        assert_eq!(pattern.extract("x a b", &split_regex), None);
+
+        assert!(pattern.matches("a 1 b", &split_regex));
+        assert!(!pattern.matches("a 1 c", &split_regex));
+    }
+
+    #[test]
+    fn test_extract_backtracks() {
+        let split_regex = Regex::new(",").unwrap();
+        let pattern = Pattern::new(vec_into![
+            PatternElement::Placeholder,
+            "x",
+            "y",
+            PatternElement::Placeholder,
+            "z",
+        ]);
+
+        assert_eq!(
+            pattern.extract("x,a,x,y,z,z", &split_regex),
+            Some(vec!["x,a", "z"])
+        );
+        assert_eq!(pattern.extract("x,y,z", &split_regex), Some(vec!["", ""]));
+        assert_eq!(pattern.extract("x,y", &split_regex), None);
+    }
+
+    #[test]
+    fn test_extract_matches_every_line_in_cluster() {
+        let split_regex = Regex::new("\\s+").unwrap();
+        let lines = [
+            "user 12 logged in from 10.0.0.1",
+            "user 13 logged in from 10.0.0.2",
+            "user 14 logged in",
+        ];
+
+        let mut clusterer = Clusterer::new(
+            ClustererOptions::default().with_max_dist(0.7),
+            split_regex.clone(),
+        );
+        for line in &lines {
+            clusterer.process_line(line);
+        }
+        let pattern = clusterer.take_result().next().unwrap().pattern;
+
+        for line in &lines {
+            assert!(pattern.matches(line, &split_regex), "{}", line);
+        }
+    }
 }
@@ -1,13 +1,14 @@
 use std::ops::{Deref, DerefMut};
 
-/// Simple arena of Strings. Strings are either dead, meaning they contain no
-/// useful data, or live, meaning that they do contain valid data. To get
-/// strings, use the `take_live` or `take_dead` functions as appropriate.
-/// Strings will automatically be returned to the opposite pool when they are
-/// dropped.
+/// Simple arena of byte strings, such as lines which haven't been decoded yet.
+/// Strings are either dead, meaning they contain no useful data, or live,
+/// meaning that they do contain valid data. To get strings, use the
+/// `take_live` or `take_dead` functions as appropriate. Strings will
+/// automatically be returned to the opposite pool when they are dropped.
 pub struct StringPool {
-    live: Vec<String>,
-    dead: Vec<String>,
+    live: Vec<Vec<u8>>,
+    dead: Vec<Vec<u8>>,
+    capacity: usize,
 }
 
 enum Target {
@@ -17,9 +18,9 @@ enum Target {
 
 /// Reference to a string from a LinePool. Will automatically be returned to the
 /// appropriate collection (live/dead) when Dropped. Implements `Deref` and
-/// `DerefMut` with `String` as the target.
+/// `DerefMut` with `Vec<u8>` as the target.
 pub struct PoolRef<'a> {
-    line: String,
+    line: Vec<u8>,
     pool: &'a mut StringPool,
     target: Target,
 }
@@ -29,14 +30,25 @@ impl StringPool {
     /// capacity for the same number of live strings.
     pub fn with_capacity(capacity: usize) -> Self {
         let mut dead = Vec::with_capacity(capacity);
-        dead.resize(capacity, String::new());
+        dead.resize(capacity, Vec::new());
 
         Self {
             dead,
             live: Vec::with_capacity(capacity),
+            capacity,
         }
     }
 
+    /// Change the number of strings in the pool to `capacity`, by adding
+    /// dead strings or dropping them. Live strings are never dropped, so the
+    /// pool may hold more than `capacity` strings until they are taken.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        let dead = capacity.saturating_sub(self.live.len());
+        self.dead.resize(dead, Vec::new());
+        self.live.reserve(capacity.saturating_sub(self.live.len()));
+        self.capacity = capacity;
+    }
+
     /// Is the pool of live strings empty
     pub fn is_empty(&self) -> bool {
         self.live.is_empty()
@@ -78,7 +90,7 @@ impl StringPool {
 
     /// Maximum number of items that can be stored in this pool
     pub fn capacity(&self) -> usize {
-        self.live.capacity()
+        self.capacity
     }
 }
 
@@ -103,7 +115,7 @@ impl<'a> Drop for PoolRef<'a> {
 }
 
 impl<'a> Deref for PoolRef<'a> {
-    type Target = String;
+    type Target = Vec<u8>;
 
     fn deref(&self) -> &Self::Target {
         &self.line
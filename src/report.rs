@@ -0,0 +1,1046 @@
+use std::{
+    borrow::Cow,
+    cmp::Reverse,
+    collections::BTreeMap,
+    fmt,
+    io::{self, Write},
+    str::FromStr,
+};
+
+use console::Style;
+use regex::Regex;
+use serde::Serialize;
+
+use crate::{
+    clusterer::Cluster,
+    diff::{PatternDiff, SharedPattern},
+    pattern::{Pattern, PatternElement},
+    tokenizer::Tokenizer,
+};
+
+/// Output format of a report
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// One `count pattern` line per cluster
+    Text,
+    /// A single JSON document
+    Json,
+    /// One JSON object per cluster, each on its own line
+    Ndjson,
+    /// Comma separated values with a header row
+    Csv,
+    /// Tab separated values with a header row
+    Tsv,
+    /// A Markdown table
+    Markdown,
+}
+
+impl Format {
+    fn is_table(self) -> bool {
+        matches!(self, Format::Csv | Format::Tsv | Format::Markdown)
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            "ndjson" => Ok(Self::Ndjson),
+            "csv" => Ok(Self::Csv),
+            "tsv" => Ok(Self::Tsv),
+            "markdown" | "md" => Ok(Self::Markdown),
+            _ => Err(format!(
+                "unknown format {:?}, expected text, json, ndjson, csv, tsv or markdown",
+                s
+            )),
+        }
+    }
+}
+
+/// When to use colors in text reports
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorChoice {
+    Always,
+    Never,
+    /// Only when stdout is a terminal and colors aren't turned off with the
+    /// `CLICOLOR` environment variables
+    Auto,
+}
+
+impl ColorChoice {
+    /// Whether to use colors for a report printed to stdout
+    pub fn for_stdout(self) -> bool {
+        match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => console::colors_enabled(),
+        }
+    }
+}
+
+impl FromStr for ColorChoice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(Self::Always),
+            "never" => Ok(Self::Never),
+            "auto" => Ok(Self::Auto),
+            _ => Err(format!(
+                "unknown color choice {:?}, expected always, never or auto",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ReportOptions {
+    pub format: Format,
+    /// Only include the N largest clusters
+    pub top: Option<usize>,
+    /// Leave out clusters with fewer lines than this
+    pub min_members: u32,
+    /// Include the id of each cluster, which is its index in the slice of
+    /// clusters the report was made from
+    pub show_ids: bool,
+    /// Render patterns as regular expressions (see `Pattern::to_regex`)
+    pub as_regex: bool,
+    /// Keep clusters in the order they were given instead of sorting them
+    /// largest first
+    pub keep_order: bool,
+    /// Render text reports with `ColoredCluster`
+    pub color: bool,
+}
+
+impl Default for ReportOptions {
+    fn default() -> Self {
+        Self {
+            format: Format::Text,
+            top: None,
+            min_members: 1,
+            show_ids: false,
+            as_regex: false,
+            keep_order: false,
+            color: false,
+        }
+    }
+}
+
+/// The clusters of a run, selected and ordered for printing
+pub struct Report<'c> {
+    clusters: Vec<(usize, &'c Cluster<'static>)>,
+    total_lines: u64,
+    unmatched: Option<u64>,
+}
+
+impl<'c> Report<'c> {
+    /// `clusters` should hold every cluster of a run regardless of size, so
+    /// that percentages are of the total number of lines. Clusters smaller
+    /// than `options.min_members` are left out of the report itself.
+    pub fn new(clusters: &'c [Cluster<'static>], options: &ReportOptions) -> Self {
+        let total_lines = clusters.iter().map(|c| c.count as u64).sum();
+
+        let mut selected: Vec<_> = clusters
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| c.count >= options.min_members)
+            .collect();
+
+        if !options.keep_order {
+            selected.sort_by_key(|(_, c)| Reverse(c.count));
+        }
+
+        selected.truncate(options.top.unwrap_or(usize::MAX));
+
+        Self {
+            clusters: selected,
+            total_lines,
+            unmatched: None,
+        }
+    }
+
+    /// Record the number of lines which didn't belong to any cluster. These
+    /// count towards the total used for percentages.
+    pub fn with_unmatched(mut self, unmatched: u64) -> Self {
+        self.total_lines += unmatched;
+        self.unmatched = Some(unmatched);
+        self
+    }
+
+    /// Override the total number of lines used for percentages, for reports
+    /// of only some of the clusters of a run
+    pub fn with_total_lines(mut self, total_lines: u64) -> Self {
+        self.total_lines = total_lines;
+        self
+    }
+
+    /// Write the report in `options.format`. `tokenizer` is what the
+    /// clustered lines were split with.
+    pub fn write(
+        &self,
+        mut writer: impl Write,
+        options: &ReportOptions,
+        tokenizer: &dyn Tokenizer,
+    ) -> io::Result<()> {
+        match options.format {
+            Format::Text => {
+                let count_width = count_width(
+                    self.clusters
+                        .iter()
+                        .map(|(_, c)| c.count as u64)
+                        .chain(self.unmatched),
+                );
+
+                for (id, cluster) in &self.clusters {
+                    if options.show_ids {
+                        write!(writer, "{}\t", id)?;
+                    }
+
+                    if options.as_regex {
+                        let regex = pattern_regex(&cluster.pattern, tokenizer)?;
+                        writeln!(writer, "{} {}", cluster.count, regex)?;
+                    } else if options.color {
+                        writeln!(writer, "{}", ColoredCluster::new(cluster, count_width))?;
+                    } else {
+                        writeln!(writer, "{}", cluster)?;
+                    }
+
+                    write_dimensions(&mut writer, cluster)?;
+                }
+
+                if let Some(unmatched) = self.unmatched {
+                    if options.color {
+                        writeln!(
+                            writer,
+                            "{} {}",
+                            count_style().apply_to(format!("{:>1$}", unmatched, count_width)),
+                            placeholder_style().apply_to("(unmatched)")
+                        )?;
+                    } else {
+                        writeln!(writer, "{} (unmatched)", unmatched)?;
+                    }
+                }
+            }
+            Format::Json => {
+                let report = JsonReport {
+                    total_lines: self.total_lines,
+                    unmatched: self.unmatched,
+                    clusters: self.json_clusters(options, tokenizer)?,
+                };
+
+                serde_json::to_writer_pretty(&mut writer, &report)?;
+                writeln!(writer)?;
+            }
+            Format::Ndjson => {
+                for cluster in self.json_clusters(options, tokenizer)? {
+                    serde_json::to_writer(&mut writer, &cluster)?;
+                    writeln!(writer)?;
+                }
+            }
+            Format::Csv | Format::Tsv | Format::Markdown => {
+                self.write_table(writer, options, tokenizer)?
+            }
+        }
+
+        Ok(())
+    }
+
+    /// One row per cluster with its rank in the report, count, percent of all
+    /// lines, and the percent of all lines covered by it and every cluster
+    /// before it. Unmatched lines get a row without a rank at the end.
+    fn write_table(
+        &self,
+        writer: impl Write,
+        options: &ReportOptions,
+        tokenizer: &dyn Tokenizer,
+    ) -> io::Result<()> {
+        let mut header = vec!["rank"];
+        if options.show_ids {
+            header.push("id");
+        }
+        header.extend(["count", "percent", "cumulative_percent", "pattern"]);
+
+        let mut table = Table::new(writer, options.format, &header)?;
+
+        let mut cumulative = 0.0;
+        for (rank, (id, cluster)) in self.clusters.iter().enumerate() {
+            let percent = percent(cluster.count, self.total_lines);
+            cumulative += percent;
+
+            let mut row = vec![(rank + 1).to_string()];
+            if options.show_ids {
+                row.push(id.to_string());
+            }
+            row.extend([
+                cluster.count.to_string(),
+                format!("{:.2}", percent),
+                format!("{:.2}", cumulative),
+            ]);
+            table.row(row, pattern_text(&cluster.pattern, options, tokenizer)?)?;
+        }
+
+        if let Some(unmatched) = self.unmatched {
+            let unmatched_percent = percent_u64(unmatched, self.total_lines);
+            cumulative += unmatched_percent;
+
+            let mut row = vec![String::new()];
+            if options.show_ids {
+                row.push(String::new());
+            }
+            row.extend([
+                unmatched.to_string(),
+                format!("{:.2}", unmatched_percent),
+                format!("{:.2}", cumulative),
+            ]);
+            table.row(row, "(unmatched)".to_string())?;
+        }
+
+        Ok(())
+    }
+
+    fn json_clusters(
+        &self,
+        options: &ReportOptions,
+        tokenizer: &dyn Tokenizer,
+    ) -> io::Result<Vec<JsonCluster<'c>>> {
+        self.clusters
+            .iter()
+            .map(|(id, cluster)| {
+                json_cluster(
+                    cluster,
+                    Some(*id).filter(|_| options.show_ids),
+                    self.total_lines,
+                    options,
+                    tokenizer,
+                )
+            })
+            .collect()
+    }
+}
+
+/// Write the result of `diff::diff`. `a` is the input and `b` is the file it
+/// was compared with.
+pub fn write_diff(
+    mut writer: impl Write,
+    diff: &PatternDiff,
+    b_name: &str,
+    options: &ReportOptions,
+    tokenizer: &dyn Tokenizer,
+) -> io::Result<()> {
+    let top = options.top.unwrap_or(usize::MAX);
+
+    match options.format {
+        Format::Text => {
+            let sides = [
+                ("only in input:".to_string(), &diff.only_a),
+                (format!("only in {}:", b_name), &diff.only_b),
+            ];
+            for (heading, clusters) in &sides {
+                writeln!(writer, "{}", heading)?;
+
+                let clusters = clusters.iter().take(top);
+                let count_width = count_width(clusters.clone().map(|c| c.count as u64));
+                for cluster in clusters {
+                    if options.color {
+                        writeln!(writer, "{}", ColoredCluster::new(cluster, count_width))?;
+                    } else {
+                        writeln!(writer, "{}", cluster)?;
+                    }
+                }
+            }
+
+            writeln!(writer, "changed:")?;
+            for shared in diff.changed.iter().take(top) {
+                if options.color {
+                    writeln!(
+                        writer,
+                        "{} -> {} ({:.2}% -> {:.2}%) {}",
+                        count_style().apply_to(shared.count_a),
+                        count_style().apply_to(shared.count_b),
+                        shared.frequency_a * 100.0,
+                        shared.frequency_b * 100.0,
+                        ColoredPattern(&shared.pattern)
+                    )?;
+                } else {
+                    writeln!(writer, "{}", shared)?;
+                }
+            }
+        }
+        Format::Json | Format::Ndjson => {
+            let report = JsonDiff {
+                total_lines_a: diff.total_a,
+                total_lines_b: diff.total_b,
+                only_a: json_side(&diff.only_a, top, diff.total_a, options, tokenizer)?,
+                only_b: json_side(&diff.only_b, top, diff.total_b, options, tokenizer)?,
+                changed: diff
+                    .changed
+                    .iter()
+                    .take(top)
+                    .map(|shared| json_shared(shared, options, tokenizer))
+                    .collect::<io::Result<_>>()?,
+            };
+
+            if options.format == Format::Json {
+                serde_json::to_writer_pretty(&mut writer, &report)?;
+            } else {
+                serde_json::to_writer(&mut writer, &report)?;
+            }
+            writeln!(writer)?;
+        }
+        Format::Csv | Format::Tsv | Format::Markdown => {
+            write_diff_table(writer, diff, top, options, tokenizer)?
+        }
+    }
+
+    Ok(())
+}
+
+fn count_style() -> Style {
+    Style::new().bold().force_styling(true)
+}
+
+fn placeholder_style() -> Style {
+    Style::new().yellow().force_styling(true)
+}
+
+fn variable_style() -> Style {
+    Style::new().cyan().force_styling(true)
+}
+
+/// Renders a cluster for a terminal, with its count right-aligned to
+/// `count_width` and placeholders and named variables (text tokens like
+/// `<ip>`, as logmine writes them) each in their own color. Colors are always
+/// written, so whether to use this should be decided beforehand, such as with
+/// `ColorChoice::for_stdout`.
+pub struct ColoredCluster<'c> {
+    cluster: &'c Cluster<'static>,
+    count_width: usize,
+}
+
+impl<'c> ColoredCluster<'c> {
+    pub fn new(cluster: &'c Cluster<'static>, count_width: usize) -> Self {
+        Self {
+            cluster,
+            count_width,
+        }
+    }
+}
+
+impl fmt::Display for ColoredCluster<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let count = format!("{:>1$}", self.cluster.count, self.count_width);
+        write!(
+            f,
+            "{} {}",
+            count_style().apply_to(count),
+            ColoredPattern(&self.cluster.pattern)
+        )
+    }
+}
+
+struct ColoredPattern<'p>(&'p Pattern<'static>);
+
+impl fmt::Display for ColoredPattern<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, element) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(&self.0.separator_text(i - 1))?;
+            }
+
+            match element {
+                PatternElement::Text(t) if is_variable(&t.to_str_lossy()) => {
+                    write!(f, "{}", variable_style().apply_to(t))?
+                }
+                PatternElement::Text(t) => write!(f, "{}", t)?,
+                PatternElement::Placeholder => {
+                    write!(f, "{}", placeholder_style().apply_to("---"))?
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether `token` names a variable, like `<ip>` or `<date_time>`
+fn is_variable(token: &str) -> bool {
+    let name = match token.strip_prefix('<').and_then(|t| t.strip_suffix('>')) {
+        Some(name) => name,
+        None => return false,
+    };
+
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Number of digits in the largest count
+fn count_width(counts: impl Iterator<Item = u64>) -> usize {
+    counts.max().unwrap_or(0).to_string().len()
+}
+
+/// A single table for all three parts of a diff, told apart by the `change`
+/// column: `only_a`, `only_b` or `changed`. Counts and percents of the side a
+/// pattern wasn't found in are left empty.
+fn write_diff_table(
+    writer: impl Write,
+    diff: &PatternDiff,
+    top: usize,
+    options: &ReportOptions,
+    tokenizer: &dyn Tokenizer,
+) -> io::Result<()> {
+    let header = [
+        "change",
+        "count_a",
+        "count_b",
+        "percent_a",
+        "percent_b",
+        "pattern",
+    ];
+    let mut table = Table::new(writer, options.format, &header)?;
+
+    for cluster in diff.only_a.iter().take(top) {
+        let row = vec![
+            "only_a".to_string(),
+            cluster.count.to_string(),
+            String::new(),
+            format!("{:.2}", percent(cluster.count, diff.total_a)),
+            String::new(),
+        ];
+        table.row(row, pattern_text(&cluster.pattern, options, tokenizer)?)?;
+    }
+
+    for cluster in diff.only_b.iter().take(top) {
+        let row = vec![
+            "only_b".to_string(),
+            String::new(),
+            cluster.count.to_string(),
+            String::new(),
+            format!("{:.2}", percent(cluster.count, diff.total_b)),
+        ];
+        table.row(row, pattern_text(&cluster.pattern, options, tokenizer)?)?;
+    }
+
+    for shared in diff.changed.iter().take(top) {
+        let row = vec![
+            "changed".to_string(),
+            shared.count_a.to_string(),
+            shared.count_b.to_string(),
+            format!("{:.2}", shared.frequency_a * 100.0),
+            format!("{:.2}", shared.frequency_b * 100.0),
+        ];
+        table.row(row, pattern_text(&shared.pattern, options, tokenizer)?)?;
+    }
+
+    Ok(())
+}
+
+/// Writes rows of a CSV, TSV or Markdown table. The last column of every
+/// table is a pattern, which is written as a code span in Markdown.
+struct Table<W> {
+    writer: W,
+    format: Format,
+}
+
+impl<W: Write> Table<W> {
+    fn new(mut writer: W, format: Format, header: &[&str]) -> io::Result<Self> {
+        debug_assert!(format.is_table());
+
+        let cells: Vec<_> = header.iter().map(|h| escape_cell(format, h)).collect();
+        write_row(&mut writer, format, &cells)?;
+
+        if format == Format::Markdown {
+            // every column but the pattern holds numbers or short labels
+            let mut alignment = vec!["---:"; header.len() - 1];
+            alignment.push("---");
+            write_row(&mut writer, format, &alignment)?;
+        }
+
+        Ok(Self { writer, format })
+    }
+
+    fn row(&mut self, cells: Vec<String>, pattern: String) -> io::Result<()> {
+        let mut cells: Vec<_> = cells.iter().map(|c| escape_cell(self.format, c)).collect();
+
+        cells.push(if self.format == Format::Markdown {
+            escape_cell(self.format, &code_span(&pattern))
+        } else {
+            escape_cell(self.format, &pattern)
+        });
+
+        write_row(&mut self.writer, self.format, &cells)
+    }
+}
+
+fn write_row(mut writer: impl Write, format: Format, cells: &[impl AsRef<str>]) -> io::Result<()> {
+    let cells: Vec<_> = cells.iter().map(|c| c.as_ref()).collect();
+
+    match format {
+        Format::Csv => writeln!(writer, "{}", cells.join(",")),
+        Format::Tsv => writeln!(writer, "{}", cells.join("\t")),
+        _ => writeln!(writer, "| {} |", cells.join(" | ")),
+    }
+}
+
+/// Escape `cell` so that it can't break the structure of a table row
+fn escape_cell(format: Format, cell: &str) -> String {
+    match format {
+        Format::Csv => {
+            if cell.contains(&[',', '"', '\n', '\r'][..]) {
+                format!("\"{}\"", cell.replace('"', "\"\""))
+            } else {
+                cell.to_string()
+            }
+        }
+        // TSV has no quoting, so the usual backslash escapes are used instead
+        Format::Tsv => cell
+            .replace('\\', "\\\\")
+            .replace('\t', "\\t")
+            .replace('\n', "\\n")
+            .replace('\r', "\\r"),
+        _ => cell.replace('|', "\\|").replace(['\n', '\r'], " "),
+    }
+}
+
+/// A Markdown code span containing `text`, with a fence longer than any run of
+/// backticks in it
+fn code_span(text: &str) -> String {
+    let longest_run = text
+        .split(|c| c != '`')
+        .map(|run| run.len())
+        .max()
+        .unwrap_or(0);
+    let fence = "`".repeat(longest_run + 1);
+
+    if text.starts_with('`') || text.ends_with('`') {
+        format!("{} {} {}", fence, text, fence)
+    } else {
+        format!("{}{}{}", fence, text, fence)
+    }
+}
+
+/// The pattern as it's shown in the text report, or as a regex
+fn pattern_text(
+    pattern: &Pattern,
+    options: &ReportOptions,
+    tokenizer: &dyn Tokenizer,
+) -> io::Result<String> {
+    if options.as_regex {
+        Ok(pattern_regex(pattern, tokenizer)?.to_string())
+    } else {
+        Ok(pattern.to_string())
+    }
+}
+
+/// Number of values of each dimension listed under a cluster in text reports
+const TEXT_DIMENSION_VALUES: usize = 5;
+
+/// Write an indented `name: value (count), ...` line for each dimension of
+/// `cluster`, listing its most common values
+fn write_dimensions(writer: &mut impl Write, cluster: &Cluster) -> io::Result<()> {
+    for (name, dimension) in &cluster.dimensions {
+        let values = dimension.by_count();
+        let mut rest = dimension.other;
+
+        write!(writer, "    {}:", name)?;
+        for (i, (value, count)) in values.iter().enumerate() {
+            if i < TEXT_DIMENSION_VALUES {
+                let comma = if i == 0 { "" } else { "," };
+                write!(writer, "{} {} ({})", comma, value, count)?;
+            } else {
+                rest += count;
+            }
+        }
+        if rest > 0 {
+            let comma = if values.is_empty() { "" } else { "," };
+            write!(writer, "{} other ({})", comma, rest)?;
+        }
+        writeln!(writer)?;
+    }
+
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct JsonReport<'c> {
+    total_lines: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unmatched: Option<u64>,
+    clusters: Vec<JsonCluster<'c>>,
+}
+
+#[derive(Serialize)]
+struct JsonCluster<'c> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<usize>,
+    count: u32,
+    /// Percentage of all lines which belong to this cluster
+    percent: f64,
+    pattern: Vec<JsonElement<'c>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    regex: Option<String>,
+    representative: Vec<Cow<'c, str>>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    dimensions: BTreeMap<&'c str, JsonDimension<'c>>,
+}
+
+#[derive(Serialize)]
+struct JsonDimension<'c> {
+    values: &'c BTreeMap<String, u32>,
+    other: u32,
+}
+
+#[derive(Serialize)]
+struct JsonDiff<'c> {
+    total_lines_a: u64,
+    total_lines_b: u64,
+    only_a: Vec<JsonCluster<'c>>,
+    only_b: Vec<JsonCluster<'c>>,
+    changed: Vec<JsonShared<'c>>,
+}
+
+#[derive(Serialize)]
+struct JsonShared<'c> {
+    count_a: u32,
+    count_b: u32,
+    percent_a: f64,
+    percent_b: f64,
+    pattern: Vec<JsonElement<'c>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    regex: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum JsonElement<'c> {
+    Text { value: Cow<'c, str> },
+    Placeholder,
+}
+
+fn json_side<'c>(
+    clusters: &'c [Cluster<'static>],
+    top: usize,
+    total_lines: u64,
+    options: &ReportOptions,
+    tokenizer: &dyn Tokenizer,
+) -> io::Result<Vec<JsonCluster<'c>>> {
+    clusters
+        .iter()
+        .take(top)
+        .map(|c| json_cluster(c, None, total_lines, options, tokenizer))
+        .collect()
+}
+
+fn json_cluster<'c>(
+    cluster: &'c Cluster<'static>,
+    id: Option<usize>,
+    total_lines: u64,
+    options: &ReportOptions,
+    tokenizer: &dyn Tokenizer,
+) -> io::Result<JsonCluster<'c>> {
+    Ok(JsonCluster {
+        id,
+        count: cluster.count,
+        percent: percent(cluster.count, total_lines),
+        pattern: json_pattern(&cluster.pattern),
+        regex: json_regex(&cluster.pattern, options, tokenizer)?,
+        representative: cluster
+            .representative
+            .iter()
+            .filter_map(|element| match element {
+                PatternElement::Text(t) => Some(t.to_str_lossy()),
+                PatternElement::Placeholder => None,
+            })
+            .collect(),
+        dimensions: cluster
+            .dimensions
+            .iter()
+            .map(|(name, d)| {
+                let dimension = JsonDimension {
+                    values: &d.values,
+                    other: d.other,
+                };
+                (name.as_str(), dimension)
+            })
+            .collect(),
+    })
+}
+
+fn json_shared<'c>(
+    shared: &'c SharedPattern,
+    options: &ReportOptions,
+    tokenizer: &dyn Tokenizer,
+) -> io::Result<JsonShared<'c>> {
+    Ok(JsonShared {
+        count_a: shared.count_a,
+        count_b: shared.count_b,
+        percent_a: shared.frequency_a * 100.0,
+        percent_b: shared.frequency_b * 100.0,
+        pattern: json_pattern(&shared.pattern),
+        regex: json_regex(&shared.pattern, options, tokenizer)?,
+    })
+}
+
+fn json_pattern<'c>(pattern: &'c Pattern<'static>) -> Vec<JsonElement<'c>> {
+    pattern
+        .iter()
+        .map(|element| match element {
+            PatternElement::Text(t) => JsonElement::Text {
+                value: t.to_str_lossy(),
+            },
+            PatternElement::Placeholder => JsonElement::Placeholder,
+        })
+        .collect()
+}
+
+fn json_regex(
+    pattern: &Pattern,
+    options: &ReportOptions,
+    tokenizer: &dyn Tokenizer,
+) -> io::Result<Option<String>> {
+    if !options.as_regex {
+        return Ok(None);
+    }
+
+    Ok(Some(pattern_regex(pattern, tokenizer)?.to_string()))
+}
+
+fn pattern_regex(pattern: &Pattern, tokenizer: &dyn Tokenizer) -> io::Result<Regex> {
+    pattern
+        .to_regex(tokenizer)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+fn percent(count: u32, total_lines: u64) -> f64 {
+    percent_u64(count as u64, total_lines)
+}
+
+fn percent_u64(count: u64, total_lines: u64) -> f64 {
+    if total_lines == 0 {
+        0.0
+    } else {
+        count as f64 * 100.0 / total_lines as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use regex::Regex;
+    use serde_json::json;
+
+    use crate::{
+        clusterer::Cluster,
+        pattern::{Pattern, PatternElement},
+    };
+
+    use super::{code_span, is_variable, Format, Report, ReportOptions};
+
+    fn clusters() -> Vec<Cluster<'static>> {
+        vec![
+            Cluster {
+                representative: Pattern::new(vec_into!["abc", "m"]),
+                count: 1,
+                pattern: Pattern::new(vec_into!["abc", "m"]),
+                dimensions: Default::default(),
+            },
+            Cluster {
+                representative: Pattern::new(vec_into!["hello", "1"]),
+                count: 3,
+                pattern: Pattern::new(vec_into!["hello", PatternElement::Placeholder]),
+                dimensions: Default::default(),
+            },
+        ]
+    }
+
+    fn render(clusters: &[Cluster<'static>], options: ReportOptions) -> String {
+        let mut out = Vec::new();
+        Report::new(clusters, &options)
+            .write(&mut out, &options, &Regex::new("\\s+").unwrap())
+            .unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_text() {
+        let options = ReportOptions {
+            min_members: 2,
+            ..Default::default()
+        };
+
+        assert_eq!(render(&clusters(), options), "3 hello ---\n");
+    }
+
+    #[test]
+    fn test_dimensions() {
+        let mut clusters = clusters();
+        let level = clusters[1].dimensions.entry("level".into()).or_default();
+        level.add("INFO", 2);
+        level.add("WARN", 1);
+
+        let options = ReportOptions {
+            min_members: 2,
+            ..Default::default()
+        };
+        assert_eq!(
+            render(&clusters, options),
+            "3 hello ---\n    level: INFO (2), WARN (1)\n"
+        );
+
+        let options = ReportOptions {
+            format: Format::Ndjson,
+            min_members: 2,
+            ..Default::default()
+        };
+        let cluster: serde_json::Value = serde_json::from_str(&render(&clusters, options)).unwrap();
+        assert_eq!(
+            cluster["dimensions"],
+            json!({"level": {"values": {"INFO": 2, "WARN": 1}, "other": 0}})
+        );
+    }
+
+    #[test]
+    fn test_json() {
+        let options = ReportOptions {
+            format: Format::Json,
+            show_ids: true,
+            ..Default::default()
+        };
+
+        let report: serde_json::Value =
+            serde_json::from_str(&render(&clusters(), options)).unwrap();
+
+        assert_eq!(
+            report,
+            json!({
+                "total_lines": 4,
+                "clusters": [
+                    {
+                        "id": 1,
+                        "count": 3,
+                        "percent": 75.0,
+                        "pattern": [
+                            {"type": "text", "value": "hello"},
+                            {"type": "placeholder"},
+                        ],
+                        "representative": ["hello", "1"],
+                    },
+                    {
+                        "id": 0,
+                        "count": 1,
+                        "percent": 25.0,
+                        "pattern": [
+                            {"type": "text", "value": "abc"},
+                            {"type": "text", "value": "m"},
+                        ],
+                        "representative": ["abc", "m"],
+                    },
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn test_ndjson() {
+        let options = ReportOptions {
+            format: Format::Ndjson,
+            top: Some(1),
+            as_regex: true,
+            ..Default::default()
+        };
+
+        let out = render(&clusters(), options);
+        let lines: Vec<serde_json::Value> = out
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0]["count"], 3);
+        assert_eq!(lines[0]["percent"], 75.0);
+        assert_eq!(lines[0]["regex"], "(?s)^hello(?:(?:\\s+)(?P<p0>.*?))?$");
+    }
+
+    #[test]
+    fn test_tables() {
+        let mut clusters = clusters();
+        clusters[0].pattern = Pattern::new(vec_into!["a,b", "|c\t"]);
+
+        let csv = ReportOptions {
+            format: Format::Csv,
+            ..Default::default()
+        };
+        assert_eq!(
+            render(&clusters, csv),
+            "rank,count,percent,cumulative_percent,pattern\n\
+             1,3,75.00,75.00,hello ---\n\
+             2,1,25.00,100.00,\"a,b |c\t\"\n"
+        );
+
+        let tsv = ReportOptions {
+            format: Format::Tsv,
+            top: Some(1),
+            show_ids: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            render(&clusters, tsv),
+            "rank\tid\tcount\tpercent\tcumulative_percent\tpattern\n\
+             1\t1\t3\t75.00\t75.00\thello ---\n"
+        );
+
+        let markdown = ReportOptions {
+            format: Format::Markdown,
+            ..Default::default()
+        };
+        assert_eq!(
+            render(&clusters, markdown),
+            "| rank | count | percent | cumulative_percent | pattern |\n\
+             | ---: | ---: | ---: | ---: | --- |\n\
+             | 1 | 3 | 75.00 | 75.00 | `hello ---` |\n\
+             | 2 | 1 | 25.00 | 100.00 | `a,b \\|c\t` |\n"
+        );
+    }
+
+    #[test]
+    fn test_colored() {
+        let mut clusters = clusters();
+        clusters.push(Cluster {
+            representative: Pattern::new(vec_into!["from", "<ip>"]),
+            count: 12,
+            pattern: Pattern::new(vec_into!["from", "<ip>"]),
+            dimensions: Default::default(),
+        });
+
+        let options = ReportOptions {
+            color: true,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            render(&clusters, options),
+            "\u{1b}[1m12\u{1b}[0m from \u{1b}[36m<ip>\u{1b}[0m\n\
+             \u{1b}[1m 3\u{1b}[0m hello \u{1b}[33m---\u{1b}[0m\n\
+             \u{1b}[1m 1\u{1b}[0m abc m\n"
+        );
+    }
+
+    #[test]
+    fn test_is_variable() {
+        assert!(is_variable("<ip>"));
+        assert!(is_variable("<date_time2>"));
+        assert!(!is_variable("<>"));
+        assert!(!is_variable("<2x>"));
+        assert!(!is_variable("<a b>"));
+        assert!(!is_variable("ip"));
+    }
+
+    #[test]
+    fn test_code_span() {
+        assert_eq!(code_span("a"), "`a`");
+        assert_eq!(code_span("a ` b"), "``a ` b``");
+        assert_eq!(code_span("`a``"), "``` `a`` ```");
+    }
+}
@@ -1,6 +1,13 @@
-use crate::pattern::{Pattern, PatternElement};
+use crate::pattern::Pattern;
 
 pub(crate) fn distance(fields1: &Pattern, fields2: &Pattern, max_dist: f64) -> f64 {
+    distance_of(fields1.items(), fields2.items(), max_dist)
+}
+
+/// Same as `distance`, for items of any kind, such as the symbols of
+/// patterns. Gives exactly the same result for items which are equal exactly
+/// when the pattern elements they stand for are.
+pub(crate) fn distance_of<T: PartialEq>(fields1: &[T], fields2: &[T], max_dist: f64) -> f64 {
     let max_len = fields1.len().max(fields2.len()) as f64;
 
     let mut total = 0.0;
@@ -15,7 +22,7 @@ pub(crate) fn distance(fields1: &Pattern, fields2: &Pattern, max_dist: f64) -> f
     1.0 - total
 }
 
-fn score(f1: &PatternElement, f2: &PatternElement) -> f64 {
+fn score<T: PartialEq>(f1: &T, f2: &T) -> f64 {
     if f1 == f2 {
         1.0
     } else {
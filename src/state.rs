@@ -0,0 +1,313 @@
+//! Versioned on-disk format for the state of a `Clusterer`, so that a run can
+//! be saved and later resumed with more input.
+
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    io::{self, Read, Write},
+    iter::FromIterator,
+};
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    clusterer::{Cluster, Clusterer, ClustererOptions, Dimension},
+    pattern::{Pattern, PatternElement},
+};
+
+/// Version written by `save`. Bump this whenever the format below changes in
+/// a way that older readers would misinterpret.
+pub const FORMAT_VERSION: u32 = 2;
+
+/// Oldest version `load` can read. Version 1 files have no dimensions.
+const MIN_FORMAT_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct SavedState<'a> {
+    version: u32,
+    options: SavedOptions,
+    split_pattern: Cow<'a, str>,
+    clusters: Vec<SavedCluster<'a>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedOptions {
+    max_dist: f64,
+    min_members: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedCluster<'a> {
+    count: u32,
+    representative: SavedPattern<'a>,
+    pattern: SavedPattern<'a>,
+    // separators and dimensions are both version 2 fields, which are optional
+    // when reading version 1 files, as those have no dimensions and may have
+    // been written before separators
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    representative_separators: SavedPattern<'a>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pattern_separators: SavedPattern<'a>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    dimensions: BTreeMap<Cow<'a, str>, SavedDimension<'a>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedDimension<'a> {
+    values: BTreeMap<Cow<'a, str>, u32>,
+    other: u32,
+}
+
+type SavedPattern<'a> = Vec<SavedElement<'a>>;
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum SavedElement<'a> {
+    Text { value: Cow<'a, str> },
+    Placeholder,
+}
+
+#[derive(Deserialize)]
+struct VersionProbe {
+    version: u32,
+}
+
+impl Clusterer {
+    /// Write the options, split pattern and clusters of this clusterer to
+    /// `writer`. The output can be passed to `Clusterer::load` to continue
+    /// processing lines where this clusterer left off.
+    pub fn save(&self, writer: impl Write) -> io::Result<()> {
+        let options = self.options();
+
+        let state = SavedState {
+            version: FORMAT_VERSION,
+            options: SavedOptions {
+                max_dist: options.max_dist,
+                min_members: options.min_members,
+            },
+            split_pattern: Cow::Borrowed(self.tokenizer().as_str()),
+            clusters: self
+                .all_clusters()
+                .iter()
+                .map(|c| SavedCluster {
+                    count: c.count,
+                    representative: save_elements(c.representative.iter()),
+                    pattern: save_elements(c.pattern.iter()),
+                    representative_separators: save_elements(c.representative.separators()),
+                    pattern_separators: save_elements(c.pattern.separators()),
+                    dimensions: c
+                        .dimensions
+                        .iter()
+                        .map(|(name, d)| (Cow::Borrowed(name.as_str()), save_dimension(d)))
+                        .collect(),
+                })
+                .collect(),
+        };
+
+        serde_json::to_writer(writer, &state)?;
+
+        Ok(())
+    }
+
+    /// Read a clusterer previously written with `Clusterer::save`
+    pub fn load(mut reader: impl Read) -> io::Result<Self> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+
+        let VersionProbe { version } = serde_json::from_slice(&data)?;
+        if !(MIN_FORMAT_VERSION..=FORMAT_VERSION).contains(&version) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "unsupported state format version {} (expected {} to {})",
+                    version, MIN_FORMAT_VERSION, FORMAT_VERSION
+                ),
+            ));
+        }
+
+        let state: SavedState = serde_json::from_slice(&data)?;
+
+        let split_regex = Regex::new(&state.split_pattern)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let options = ClustererOptions::default()
+            .with_max_dist(state.options.max_dist)
+            .with_min_members(state.options.min_members);
+
+        let clusters = state
+            .clusters
+            .into_iter()
+            .map(|c| {
+                Ok(Cluster {
+                    representative: load_pattern(c.representative, c.representative_separators)?,
+                    count: c.count,
+                    pattern: load_pattern(c.pattern, c.pattern_separators)?,
+                    dimensions: c
+                        .dimensions
+                        .into_iter()
+                        .map(|(name, d)| (name.into_owned(), load_dimension(d)))
+                        .collect(),
+                })
+            })
+            .collect::<io::Result<_>>()?;
+
+        Ok(Clusterer::from_parts(options, split_regex, clusters))
+    }
+}
+
+fn save_elements<'a>(elements: impl Iterator<Item = &'a PatternElement<'a>>) -> SavedPattern<'a> {
+    elements
+        .map(|element| match element {
+            PatternElement::Text(t) => SavedElement::Text {
+                value: t.to_str_lossy(),
+            },
+            PatternElement::Placeholder => SavedElement::Placeholder,
+        })
+        .collect()
+}
+
+fn save_dimension(dimension: &Dimension) -> SavedDimension<'_> {
+    SavedDimension {
+        values: dimension
+            .values
+            .iter()
+            .map(|(value, count)| (Cow::Borrowed(value.as_str()), *count))
+            .collect(),
+        other: dimension.other,
+    }
+}
+
+fn load_dimension(dimension: SavedDimension<'_>) -> Dimension {
+    Dimension {
+        values: dimension
+            .values
+            .into_iter()
+            .map(|(value, count)| (value.into_owned(), count))
+            .collect(),
+        other: dimension.other,
+    }
+}
+
+fn load_pattern(
+    items: SavedPattern<'_>,
+    separators: SavedPattern<'_>,
+) -> io::Result<Pattern<'static>> {
+    let items = load_elements(items);
+
+    if separators.is_empty() {
+        return Ok(Pattern::new(items));
+    }
+
+    if separators.len() + 1 != items.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "pattern of {} elements has {} separators",
+                items.len(),
+                separators.len()
+            ),
+        ));
+    }
+
+    Ok(Pattern::with_separators(items, load_elements(separators)))
+}
+
+fn load_elements<T: FromIterator<PatternElement<'static>>>(elements: SavedPattern<'_>) -> T {
+    elements
+        .into_iter()
+        .map(|element| match element {
+            SavedElement::Text { value } => PatternElement::Text(value.into_owned().into()),
+            SavedElement::Placeholder => PatternElement::Placeholder,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use regex::Regex;
+
+    use crate::clusterer::{Clusterer, ClustererOptions, Dimension};
+
+    #[test]
+    fn test_save_and_resume() {
+        let options = ClustererOptions::default()
+            .with_max_dist(0.5)
+            .with_min_members(2);
+
+        let mut uninterrupted = Clusterer::new(options, Regex::new(",").unwrap());
+        let mut saved = Clusterer::new(options, Regex::new(",").unwrap());
+
+        for line in &["hello,1,y,3", "abc,m,n,q", "hello,1,x,3"] {
+            uninterrupted.process_line(line);
+            saved.process_line(line);
+        }
+
+        let mut file = Vec::new();
+        saved.save(&mut file).unwrap();
+        let mut resumed = Clusterer::load(file.as_slice()).unwrap();
+
+        assert_eq!(resumed.tokenizer().as_str(), ",");
+        assert_eq!(resumed.options().min_members, 2);
+
+        for line in &["abc,m,n,r", "hello,2,z,3"] {
+            uninterrupted.process_line(line);
+            resumed.process_line(line);
+        }
+
+        assert_eq!(
+            resumed.take_result().collect::<Vec<_>>(),
+            uninterrupted.take_result().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_saves_dimensions() {
+        let mut clusterer = Clusterer::new(ClustererOptions::default(), Regex::new(" ").unwrap());
+        clusterer.process_line("a b");
+        let mut cluster = clusterer.take_result().next().unwrap();
+        let mut level = Dimension::default();
+        level.add("INFO", 2);
+        level.add("WARN", 1);
+        level.other = 4;
+        cluster.dimensions.insert("level".to_string(), level);
+
+        let clusterer = Clusterer::from_parts(
+            ClustererOptions::default(),
+            Regex::new(" ").unwrap(),
+            vec![cluster],
+        );
+
+        let mut file = Vec::new();
+        clusterer.save(&mut file).unwrap();
+        let loaded = Clusterer::load(file.as_slice()).unwrap();
+
+        assert_eq!(loaded.all_clusters(), clusterer.all_clusters());
+    }
+
+    #[test]
+    fn test_loads_version_1() {
+        let file = br#"{
+            "version": 1,
+            "options": {"max_dist": 0.5, "min_members": 1},
+            "split_pattern": " ",
+            "clusters": [{
+                "count": 2,
+                "representative": [{"type": "text", "value": "a"}],
+                "pattern": [{"type": "placeholder"}]
+            }]
+        }"#;
+
+        let loaded = Clusterer::load(&file[..]).unwrap();
+
+        assert_eq!(loaded.all_clusters()[0].to_string(), "2 ---");
+        assert!(loaded.all_clusters()[0].dimensions.is_empty());
+    }
+
+    #[test]
+    fn test_rejects_unknown_version() {
+        let err = Clusterer::load(&br#"{"version": 9999}"#[..]).err().unwrap();
+
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,198 @@
+//! Table of the text of tokens, so that tokens can be compared as integers.
+//!
+//! A `Clusterer` keeps the symbols of the representative and pattern of each
+//! cluster alongside them, and compares lines to clusters by symbol. The text
+//! of the patterns of clusters is shared with the table (see `Text::Shared`),
+//! so each distinct token is stored once however many clusters contain it,
+//! and patterns remain a readable view of the clusters.
+
+use std::collections::HashMap;
+
+use parking_lot::RwLock;
+
+use crate::pattern::{Pattern, PatternElement, Text};
+
+/// Id of a piece of text in a `Symbols` table
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Symbol(u32);
+
+impl Symbol {
+    /// Stands for `PatternElement::Placeholder`
+    pub const PLACEHOLDER: Symbol = Symbol(u32::MAX);
+    /// Stands for text which isn't in the table, and so differs from the text
+    /// of every symbol which is
+    pub const UNKNOWN: Symbol = Symbol(u32::MAX - 1);
+}
+
+/// Interned text, which can be shared between threads. Each distinct text is
+/// stored once, however many times it is interned.
+#[derive(Debug, Default)]
+pub struct Symbols {
+    inner: RwLock<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// Keyed by bytes (see `Text::as_bytes`), so raw bytes and text share
+    /// symbols
+    ids: HashMap<Text<'static>, Symbol>,
+    texts: Vec<Text<'static>>,
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Symbol of `text`, adding it to the table if it isn't there yet
+    pub fn intern(&self, text: &str) -> Symbol {
+        if let Some(&symbol) = self.inner.read().ids.get(text.as_bytes()) {
+            return symbol;
+        }
+
+        self.inner.write().intern(&Text::Borrowed(text))
+    }
+
+    /// Symbol of `text`, or `Symbol::UNKNOWN` if it isn't in the table
+    pub fn get(&self, text: &str) -> Symbol {
+        self.inner.read().get(text.as_bytes())
+    }
+
+    /// Text of `symbol`, if it is in the table
+    pub fn resolve(&self, symbol: Symbol) -> Option<Text<'static>> {
+        self.inner.read().texts.get(symbol.0 as usize).cloned()
+    }
+
+    /// Number of distinct texts in the table
+    pub fn len(&self) -> usize {
+        self.inner.read().texts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Copy of `pattern` whose text, of both items and separators, is shared
+    /// with this table, interning any text not in it yet. Replaces `keys`
+    /// with the symbol of each item.
+    pub(crate) fn share_pattern(
+        &self,
+        pattern: &Pattern,
+        keys: &mut Vec<Symbol>,
+    ) -> Pattern<'static> {
+        let mut inner = self.inner.write();
+        let mut share = |element: &PatternElement| match element {
+            PatternElement::Text(t) => {
+                let symbol = inner.intern(t);
+                let text = inner.texts[symbol.0 as usize].clone();
+                (symbol, PatternElement::Text(text))
+            }
+            PatternElement::Placeholder => (Symbol::PLACEHOLDER, PatternElement::Placeholder),
+        };
+
+        keys.clear();
+        let items = pattern
+            .iter()
+            .map(|element| {
+                let (symbol, element) = share(element);
+                keys.push(symbol);
+                element
+            })
+            .collect();
+
+        if pattern.separators().count() + 1 != pattern.len() {
+            return Pattern::new(items);
+        }
+        let separators = pattern
+            .separators()
+            .map(|element| share(element).1)
+            .collect();
+        Pattern::with_separators(items, separators)
+    }
+
+    /// Replace `keys` with the symbol of each item of `pattern`, with
+    /// `Symbol::UNKNOWN` for text not in the table
+    pub(crate) fn lookup_pattern(&self, pattern: &Pattern, keys: &mut Vec<Symbol>) {
+        let inner = self.inner.read();
+        keys.clear();
+        keys.extend(pattern.iter().map(|element| match element {
+            PatternElement::Text(t) => inner.get(t.as_bytes()),
+            PatternElement::Placeholder => Symbol::PLACEHOLDER,
+        }));
+    }
+}
+
+impl Inner {
+    fn get(&self, bytes: &[u8]) -> Symbol {
+        self.ids.get(bytes).copied().unwrap_or(Symbol::UNKNOWN)
+    }
+
+    fn intern(&mut self, text: &Text) -> Symbol {
+        if let Some(&symbol) = self.ids.get(text.as_bytes()) {
+            return symbol;
+        }
+
+        let symbol = Symbol(self.texts.len() as u32);
+        assert!(symbol < Symbol::UNKNOWN, "too many distinct tokens");
+
+        let text = text.to_shared();
+        self.texts.push(text.clone());
+        self.ids.insert(text, symbol);
+        symbol
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::pattern::{Pattern, PatternElement, Text};
+
+    use super::{Symbol, Symbols};
+
+    #[test]
+    fn test_intern() {
+        let symbols = Symbols::new();
+
+        let a = symbols.intern("a");
+        let b = symbols.intern("b");
+        assert_ne!(a, b);
+        assert_eq!(symbols.intern("a"), a);
+        assert_eq!(symbols.get("b"), b);
+        assert_eq!(symbols.get("c"), Symbol::UNKNOWN);
+        assert_eq!(symbols.resolve(b).unwrap().to_string(), "b");
+        assert_eq!(symbols.len(), 2);
+    }
+
+    #[test]
+    fn test_patterns() {
+        let symbols = Symbols::new();
+        let mut pattern = Pattern::default();
+        pattern.push_text("x").push_placeholder().push_text("y");
+
+        let mut keys = Vec::new();
+        symbols.lookup_pattern(&pattern, &mut keys);
+        assert_eq!(
+            keys,
+            vec![Symbol::UNKNOWN, Symbol::PLACEHOLDER, Symbol::UNKNOWN]
+        );
+
+        let shared = symbols.share_pattern(&pattern, &mut keys);
+        assert_eq!(shared, pattern);
+        let mut looked_up = Vec::new();
+        symbols.lookup_pattern(&pattern, &mut looked_up);
+        assert_eq!(keys, looked_up);
+        assert_eq!(keys[1], Symbol::PLACEHOLDER);
+        assert_eq!(symbols.resolve(keys[2]).unwrap().to_string(), "y");
+
+        // the text of the copy is the text in the table
+        let y = symbols.resolve(keys[2]);
+        let element = shared.iter().nth(2);
+        match (element, y) {
+            (Some(PatternElement::Text(Text::Shared(text))), Some(Text::Shared(y))) => {
+                assert!(Arc::ptr_eq(text, &y))
+            }
+            other => panic!("{:?} is not shared", other),
+        }
+    }
+}
@@ -0,0 +1,798 @@
+//! Ways of splitting lines into the tokens that patterns are made of
+
+use std::{cell::RefCell, collections::HashMap, ops::Range};
+
+use regex::{bytes, Regex};
+
+/// Splits lines into tokens. The text between two consecutive tokens is kept
+/// as the separator between them (see `Pattern::separator`), while any text
+/// before the first token or after the last one is dropped.
+pub trait Tokenizer {
+    /// Call `on_token` with the byte range of each token of `line`, in order.
+    /// Ranges must not overlap.
+    fn tokenize(&self, line: &str, on_token: &mut dyn FnMut(Range<usize>));
+
+    /// Same as `tokenize`, for a line of raw bytes which may not be UTF-8.
+    /// Regexes are matched against the bytes, so that a class like `\s` only
+    /// matches chars which are valid UTF-8.
+    fn tokenize_bytes(&self, line: &[u8], on_token: &mut dyn FnMut(Range<usize>));
+
+    /// Regex matching any separator this tokenizer may leave between two
+    /// tokens. Used by `Pattern::to_regex` where the lines of a pattern were
+    /// separated by different text.
+    fn separator_regex(&self) -> String;
+
+    /// Regex matching the text this tokenizer may drop before the first token
+    /// and after the last one, if it drops any
+    fn ends_regex(&self) -> Option<String> {
+        None
+    }
+
+    /// Tokenize `line` as `tokenize` does, also calling `on_field` with the
+    /// index (see `field_name`) and byte range of each field of `line` which
+    /// isn't part of its tokens, such as a timestamp or log level. Clusters
+    /// count the lines with each value as a dimension (see
+    /// `Cluster::dimensions`).
+    fn tokenize_with_fields(
+        &self,
+        line: &str,
+        on_token: &mut dyn FnMut(Range<usize>),
+        _on_field: &mut dyn FnMut(usize, Range<usize>),
+    ) {
+        self.tokenize(line, on_token)
+    }
+
+    /// Name of the field with this index. Only called with indexes passed to
+    /// the `on_field` of `tokenize_with_fields`.
+    fn field_name(&self, _index: usize) -> &str {
+        unreachable!("tokenizer has no fields")
+    }
+}
+
+/// Tokens are the text between matches of the regex, as with `Regex::split`
+impl Tokenizer for Regex {
+    fn tokenize(&self, line: &str, on_token: &mut dyn FnMut(Range<usize>)) {
+        let mut start = 0;
+        for separator in self.find_iter(line) {
+            on_token(start..separator.start());
+            start = separator.end();
+        }
+        on_token(start..line.len());
+    }
+
+    fn tokenize_bytes(&self, line: &[u8], on_token: &mut dyn FnMut(Range<usize>)) {
+        BYTES_REGEXES.with(|regexes| {
+            if !regexes.borrow().contains_key(self.as_str()) {
+                let regex = bytes_regex(self);
+                regexes
+                    .borrow_mut()
+                    .insert(self.as_str().to_string(), regex);
+            }
+
+            split_bytes(&regexes.borrow()[self.as_str()], line, on_token);
+        });
+    }
+
+    fn separator_regex(&self) -> String {
+        self.as_str().to_string()
+    }
+}
+
+thread_local! {
+    /// `bytes_regex` of each `Regex` which has split raw bytes on this thread,
+    /// by pattern, since a `Regex` can't hold one of its own
+    static BYTES_REGEXES: RefCell<HashMap<String, bytes::Regex>> = RefCell::new(HashMap::new());
+}
+
+/// The same regex as `regex`, for matching raw bytes
+fn bytes_regex(regex: &Regex) -> bytes::Regex {
+    bytes::Regex::new(regex.as_str()).expect("a valid regex is a valid bytes regex")
+}
+
+/// Tokens are the bytes between matches of a `regex::bytes::Regex`, for lines
+/// of raw bytes. A regex like `\s+` only matches whitespace which is valid
+/// UTF-8, and text lines are split on their UTF-8 bytes.
+#[derive(Debug, Clone)]
+pub struct BytesRegex(pub bytes::Regex);
+
+impl Tokenizer for BytesRegex {
+    fn tokenize(&self, line: &str, on_token: &mut dyn FnMut(Range<usize>)) {
+        split_bytes(&self.0, line.as_bytes(), on_token);
+    }
+
+    fn tokenize_bytes(&self, line: &[u8], on_token: &mut dyn FnMut(Range<usize>)) {
+        split_bytes(&self.0, line, on_token);
+    }
+
+    fn separator_regex(&self) -> String {
+        self.0.as_str().to_string()
+    }
+}
+
+fn split_bytes(regex: &bytes::Regex, line: &[u8], on_token: &mut dyn FnMut(Range<usize>)) {
+    let mut start = 0;
+    for separator in regex.find_iter(line) {
+        on_token(start..separator.start());
+        start = separator.end();
+    }
+    on_token(start..line.len());
+}
+
+/// Tokens are the matches of the regex, and everything between them is a
+/// separator
+#[derive(Debug, Clone)]
+pub struct RegexFind {
+    regex: Regex,
+    /// `regex`, for lines of raw bytes
+    bytes: bytes::Regex,
+}
+
+impl RegexFind {
+    pub fn new(regex: Regex) -> Self {
+        let bytes = bytes_regex(&regex);
+        Self { regex, bytes }
+    }
+}
+
+impl Tokenizer for RegexFind {
+    fn tokenize(&self, line: &str, on_token: &mut dyn FnMut(Range<usize>)) {
+        for token in self.regex.find_iter(line) {
+            on_token(token.range());
+        }
+    }
+
+    fn tokenize_bytes(&self, line: &[u8], on_token: &mut dyn FnMut(Range<usize>)) {
+        for token in self.bytes.find_iter(line) {
+            on_token(token.range());
+        }
+    }
+
+    fn separator_regex(&self) -> String {
+        ".*?".to_string()
+    }
+
+    fn ends_regex(&self) -> Option<String> {
+        Some(".*?".to_string())
+    }
+}
+
+/// Tokens are separated by runs of any of a set of ASCII characters, the same
+/// as splitting on a regex like `[ \t]+` but without the overhead of a regex
+#[derive(Debug, Clone)]
+pub struct Delimiters {
+    is_delimiter: [bool; 128],
+}
+
+impl Delimiters {
+    /// Fails if any of `delimiters` isn't ASCII
+    pub fn new(delimiters: &str) -> Result<Self, String> {
+        let mut is_delimiter = [false; 128];
+        for c in delimiters.chars() {
+            if !c.is_ascii() {
+                return Err(format!("delimiter {:?} is not ASCII", c));
+            }
+            is_delimiter[c as usize] = true;
+        }
+
+        Ok(Self { is_delimiter })
+    }
+
+    /// Split on spaces, tabs, and other ASCII whitespace
+    pub fn whitespace() -> Self {
+        Self::ascii(b" \t\n\r\x0b\x0c")
+    }
+
+    fn ascii(delimiters: &[u8]) -> Self {
+        let mut is_delimiter = [false; 128];
+        for &b in delimiters {
+            is_delimiter[b as usize] = true;
+        }
+
+        Self { is_delimiter }
+    }
+
+    fn contains(&self, b: u8) -> bool {
+        self.is_delimiter.get(b as usize).copied().unwrap_or(false)
+    }
+
+    /// Tokenize `line`, ignoring any delimiter for which `is_protected`
+    /// returns true. `is_protected` is called in order for every byte except
+    /// those after the first delimiter of a run.
+    fn tokenize_with(
+        &self,
+        bytes: &[u8],
+        on_token: &mut dyn FnMut(Range<usize>),
+        mut is_protected: impl FnMut(u8) -> bool,
+    ) {
+        let mut start = 0;
+        let mut i = 0;
+        while i < bytes.len() {
+            if is_protected(bytes[i]) || !self.contains(bytes[i]) {
+                i += 1;
+                continue;
+            }
+
+            on_token(start..i);
+            while i < bytes.len() && self.contains(bytes[i]) {
+                i += 1;
+            }
+            start = i;
+        }
+        on_token(start..bytes.len());
+    }
+}
+
+impl Default for Delimiters {
+    fn default() -> Self {
+        Self::whitespace()
+    }
+}
+
+impl Tokenizer for Delimiters {
+    fn tokenize(&self, line: &str, on_token: &mut dyn FnMut(Range<usize>)) {
+        self.tokenize_bytes(line.as_bytes(), on_token);
+    }
+
+    fn tokenize_bytes(&self, line: &[u8], on_token: &mut dyn FnMut(Range<usize>)) {
+        self.tokenize_with(line, on_token, |_| false);
+    }
+
+    fn separator_regex(&self) -> String {
+        let class: String = (0..128u8)
+            .filter(|b| self.contains(*b))
+            .map(|b| format!("\\x{:02X}", b))
+            .collect();
+
+        format!("[{}]+", class)
+    }
+}
+
+/// Like `Delimiters`, but delimiters inside `"double quotes"` or `[square
+/// brackets]` don't end a token, so quoted strings and bracketed groups are
+/// kept as single tokens. Inside quotes a backslash escapes the next
+/// character, and brackets may be nested. An unterminated quote or bracket
+/// runs to the end of the line.
+#[derive(Debug, Clone, Default)]
+pub struct QuoteAware {
+    delimiters: Delimiters,
+}
+
+impl QuoteAware {
+    pub fn new(delimiters: Delimiters) -> Self {
+        Self { delimiters }
+    }
+}
+
+impl Tokenizer for QuoteAware {
+    fn tokenize(&self, line: &str, on_token: &mut dyn FnMut(Range<usize>)) {
+        self.tokenize_bytes(line.as_bytes(), on_token);
+    }
+
+    fn tokenize_bytes(&self, line: &[u8], on_token: &mut dyn FnMut(Range<usize>)) {
+        let mut in_quotes = false;
+        let mut escaped = false;
+        let mut depth = 0usize;
+
+        self.delimiters.tokenize_with(line, on_token, |b| {
+            if in_quotes {
+                match b {
+                    _ if escaped => escaped = false,
+                    b'\\' => escaped = true,
+                    b'"' => in_quotes = false,
+                    _ => {}
+                }
+                return true;
+            }
+
+            match b {
+                b'"' => in_quotes = true,
+                b'[' => depth += 1,
+                b']' => depth = depth.saturating_sub(1),
+                _ => {}
+            }
+
+            depth > 0
+        });
+    }
+
+    fn separator_regex(&self) -> String {
+        self.delimiters.separator_regex()
+    }
+}
+
+/// Wraps another tokenizer, further splitting its tokens at any of a set of
+/// ASCII characters, each of which is kept as a token of its own. `user=123`
+/// becomes `user`, `=` and `123`, so that lines which differ only in a value
+/// line up as `user=---` rather than differing in one opaque token. The parts
+/// of a split token have empty separators between them, so they are shown
+/// joined back together.
+#[derive(Debug, Clone)]
+pub struct KeepDelimiters<T> {
+    inner: T,
+    delimiters: Delimiters,
+}
+
+impl<T> KeepDelimiters<T> {
+    pub fn new(inner: T, delimiters: Delimiters) -> Self {
+        Self { inner, delimiters }
+    }
+
+    /// Keep `:`, `=`, `,` and `/` as tokens
+    pub fn punctuation(inner: T) -> Self {
+        Self::new(inner, Delimiters::ascii(b":=,/"))
+    }
+
+    /// Split `token`, a range of `bytes` given by the inner tokenizer
+    fn split(&self, bytes: &[u8], token: Range<usize>, on_token: &mut dyn FnMut(Range<usize>)) {
+        let mut start = token.start;
+        for i in token.clone() {
+            if self.delimiters.contains(bytes[i]) {
+                if start < i {
+                    on_token(start..i);
+                }
+                on_token(i..i + 1);
+                start = i + 1;
+            }
+        }
+
+        // tokens without any delimiters are passed on as they are, even if
+        // empty
+        if start < token.end || start == token.start {
+            on_token(start..token.end);
+        }
+    }
+}
+
+impl<T: Tokenizer> Tokenizer for KeepDelimiters<T> {
+    fn tokenize(&self, line: &str, on_token: &mut dyn FnMut(Range<usize>)) {
+        self.tokenize_with_fields(line, on_token, &mut |_, _| {});
+    }
+
+    fn tokenize_bytes(&self, line: &[u8], on_token: &mut dyn FnMut(Range<usize>)) {
+        self.inner
+            .tokenize_bytes(line, &mut |token| self.split(line, token, on_token));
+    }
+
+    fn separator_regex(&self) -> String {
+        format!("(?:{})?", self.inner.separator_regex())
+    }
+
+    fn ends_regex(&self) -> Option<String> {
+        self.inner.ends_regex()
+    }
+
+    fn tokenize_with_fields(
+        &self,
+        line: &str,
+        on_token: &mut dyn FnMut(Range<usize>),
+        on_field: &mut dyn FnMut(usize, Range<usize>),
+    ) {
+        self.inner.tokenize_with_fields(
+            line,
+            &mut |token| self.split(line.as_bytes(), token, on_token),
+            on_field,
+        );
+    }
+
+    fn field_name(&self, index: usize) -> &str {
+        self.inner.field_name(index)
+    }
+}
+
+/// Wraps another tokenizer, only tokenizing the part of each line captured by
+/// the group named `msg` of a regex. The other named groups are reported as
+/// fields, so that a header such as `^(?P<time>\S+) (?P<level>\w+) (?P<msg>.*)$`
+/// doesn't split otherwise identical messages into different clusters. Lines
+/// which don't match the regex are tokenized whole.
+#[derive(Debug, Clone)]
+pub struct MessageRegex<T> {
+    regex: Regex,
+    /// Name and group index of each named group other than `msg`
+    fields: Vec<(String, usize)>,
+    inner: T,
+}
+
+impl<T> MessageRegex<T> {
+    /// Fails if `regex` has no group named `msg`
+    pub fn new(regex: Regex, inner: T) -> Result<Self, String> {
+        if !regex.capture_names().any(|name| name == Some("msg")) {
+            return Err(format!("{:?} has no group named \"msg\"", regex.as_str()));
+        }
+
+        let fields = regex
+            .capture_names()
+            .enumerate()
+            .filter_map(|(i, name)| Some((name?.to_string(), i)))
+            .filter(|(name, _)| name != "msg")
+            .collect();
+
+        Ok(Self {
+            regex,
+            fields,
+            inner,
+        })
+    }
+}
+
+impl<T: Tokenizer> Tokenizer for MessageRegex<T> {
+    fn tokenize(&self, line: &str, on_token: &mut dyn FnMut(Range<usize>)) {
+        self.tokenize_with_fields(line, on_token, &mut |_, _| {});
+    }
+
+    /// The regex is matched against the line decoded lossily, as a header
+    /// regex such as `(?P<msg>.*)` couldn't otherwise match any bytes which
+    /// aren't UTF-8, while the message is split on its raw bytes
+    fn tokenize_bytes(&self, line: &[u8], on_token: &mut dyn FnMut(Range<usize>)) {
+        let text = String::from_utf8_lossy(line);
+        let message = self
+            .regex
+            .captures(&text)
+            .and_then(|c| c.name("msg"))
+            .map_or(0..text.len(), |m| m.range());
+
+        let offset = lossy_offset(line, message.start);
+        let end = lossy_offset(line, message.end);
+        self.inner.tokenize_bytes(&line[offset..end], &mut |token| {
+            on_token(token.start + offset..token.end + offset)
+        });
+    }
+
+    fn separator_regex(&self) -> String {
+        self.inner.separator_regex()
+    }
+
+    fn ends_regex(&self) -> Option<String> {
+        Some(".*?".to_string())
+    }
+
+    fn tokenize_with_fields(
+        &self,
+        line: &str,
+        on_token: &mut dyn FnMut(Range<usize>),
+        on_field: &mut dyn FnMut(usize, Range<usize>),
+    ) {
+        let captures = self.regex.captures(line);
+
+        let message = captures
+            .as_ref()
+            .and_then(|c| c.name("msg"))
+            .map_or(0..line.len(), |m| m.range());
+
+        let offset = message.start;
+        self.inner.tokenize(&line[message], &mut |token| {
+            on_token(token.start + offset..token.end + offset)
+        });
+
+        if let Some(captures) = captures {
+            for (index, (_, group)) in self.fields.iter().enumerate() {
+                if let Some(value) = captures.get(*group) {
+                    on_field(index, value.range());
+                }
+            }
+        }
+    }
+
+    fn field_name(&self, index: usize) -> &str {
+        &self.fields[index].0
+    }
+}
+
+/// Offset in `bytes` of `offset` in `String::from_utf8_lossy(bytes)`, which
+/// has a 3 byte U+FFFD in place of each invalid sequence. `offset` must be on a
+/// char boundary.
+fn lossy_offset(mut bytes: &[u8], mut offset: usize) -> usize {
+    let mut start = 0;
+    loop {
+        let (valid, invalid) = match std::str::from_utf8(bytes) {
+            Ok(_) => return start + offset,
+            Err(e) => (
+                e.valid_up_to(),
+                e.error_len().unwrap_or(bytes.len() - e.valid_up_to()),
+            ),
+        };
+        if offset <= valid {
+            return start + offset;
+        }
+
+        offset -= valid + '\u{fffd}'.len_utf8();
+        start += valid + invalid;
+        bytes = &bytes[valid + invalid..];
+    }
+}
+
+/// The tokenizers which can be chosen from the command line
+#[derive(Debug, Clone)]
+pub enum BuiltinTokenizer {
+    Split(Regex),
+    Bytes(BytesRegex),
+    Find(RegexFind),
+    Delimiters(Delimiters),
+    QuoteAware(QuoteAware),
+    KeepDelimiters(Box<KeepDelimiters<BuiltinTokenizer>>),
+    Message(Box<MessageRegex<BuiltinTokenizer>>),
+}
+
+impl Tokenizer for BuiltinTokenizer {
+    fn tokenize(&self, line: &str, on_token: &mut dyn FnMut(Range<usize>)) {
+        match self {
+            BuiltinTokenizer::Split(t) => t.tokenize(line, on_token),
+            BuiltinTokenizer::Bytes(t) => t.tokenize(line, on_token),
+            BuiltinTokenizer::Find(t) => t.tokenize(line, on_token),
+            BuiltinTokenizer::Delimiters(t) => t.tokenize(line, on_token),
+            BuiltinTokenizer::QuoteAware(t) => t.tokenize(line, on_token),
+            BuiltinTokenizer::KeepDelimiters(t) => t.tokenize(line, on_token),
+            BuiltinTokenizer::Message(t) => t.tokenize(line, on_token),
+        }
+    }
+
+    fn tokenize_bytes(&self, line: &[u8], on_token: &mut dyn FnMut(Range<usize>)) {
+        match self {
+            BuiltinTokenizer::Split(t) => t.tokenize_bytes(line, on_token),
+            BuiltinTokenizer::Bytes(t) => t.tokenize_bytes(line, on_token),
+            BuiltinTokenizer::Find(t) => t.tokenize_bytes(line, on_token),
+            BuiltinTokenizer::Delimiters(t) => t.tokenize_bytes(line, on_token),
+            BuiltinTokenizer::QuoteAware(t) => t.tokenize_bytes(line, on_token),
+            BuiltinTokenizer::KeepDelimiters(t) => t.tokenize_bytes(line, on_token),
+            BuiltinTokenizer::Message(t) => t.tokenize_bytes(line, on_token),
+        }
+    }
+
+    fn separator_regex(&self) -> String {
+        match self {
+            BuiltinTokenizer::Split(t) => t.separator_regex(),
+            BuiltinTokenizer::Bytes(t) => t.separator_regex(),
+            BuiltinTokenizer::Find(t) => t.separator_regex(),
+            BuiltinTokenizer::Delimiters(t) => t.separator_regex(),
+            BuiltinTokenizer::QuoteAware(t) => t.separator_regex(),
+            BuiltinTokenizer::KeepDelimiters(t) => t.separator_regex(),
+            BuiltinTokenizer::Message(t) => t.separator_regex(),
+        }
+    }
+
+    fn ends_regex(&self) -> Option<String> {
+        match self {
+            BuiltinTokenizer::Split(t) => t.ends_regex(),
+            BuiltinTokenizer::Bytes(t) => t.ends_regex(),
+            BuiltinTokenizer::Find(t) => t.ends_regex(),
+            BuiltinTokenizer::Delimiters(t) => t.ends_regex(),
+            BuiltinTokenizer::QuoteAware(t) => t.ends_regex(),
+            BuiltinTokenizer::KeepDelimiters(t) => t.ends_regex(),
+            BuiltinTokenizer::Message(t) => t.ends_regex(),
+        }
+    }
+
+    fn tokenize_with_fields(
+        &self,
+        line: &str,
+        on_token: &mut dyn FnMut(Range<usize>),
+        on_field: &mut dyn FnMut(usize, Range<usize>),
+    ) {
+        match self {
+            BuiltinTokenizer::Split(t) => t.tokenize_with_fields(line, on_token, on_field),
+            BuiltinTokenizer::Bytes(t) => t.tokenize_with_fields(line, on_token, on_field),
+            BuiltinTokenizer::Find(t) => t.tokenize_with_fields(line, on_token, on_field),
+            BuiltinTokenizer::Delimiters(t) => t.tokenize_with_fields(line, on_token, on_field),
+            BuiltinTokenizer::QuoteAware(t) => t.tokenize_with_fields(line, on_token, on_field),
+            BuiltinTokenizer::KeepDelimiters(t) => t.tokenize_with_fields(line, on_token, on_field),
+            BuiltinTokenizer::Message(t) => t.tokenize_with_fields(line, on_token, on_field),
+        }
+    }
+
+    fn field_name(&self, index: usize) -> &str {
+        match self {
+            BuiltinTokenizer::Split(t) => t.field_name(index),
+            BuiltinTokenizer::Bytes(t) => t.field_name(index),
+            BuiltinTokenizer::Find(t) => t.field_name(index),
+            BuiltinTokenizer::Delimiters(t) => t.field_name(index),
+            BuiltinTokenizer::QuoteAware(t) => t.field_name(index),
+            BuiltinTokenizer::KeepDelimiters(t) => t.field_name(index),
+            BuiltinTokenizer::Message(t) => t.field_name(index),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use regex::Regex;
+
+    use crate::clusterer::{Clusterer, ClustererOptions};
+
+    use super::{
+        BytesRegex, Delimiters, KeepDelimiters, MessageRegex, QuoteAware, RegexFind, Tokenizer,
+    };
+
+    fn tokens<'l>(tokenizer: &impl Tokenizer, line: &'l str) -> Vec<&'l str> {
+        let mut tokens = Vec::new();
+        tokenizer.tokenize(line, &mut |range| tokens.push(&line[range]));
+        tokens
+    }
+
+    fn byte_tokens<'l>(tokenizer: &impl Tokenizer, line: &'l [u8]) -> Vec<&'l [u8]> {
+        let mut tokens = Vec::new();
+        tokenizer.tokenize_bytes(line, &mut |range| tokens.push(&line[range]));
+        tokens
+    }
+
+    #[test]
+    fn test_split_and_find() {
+        let line = " a  b=1,c ";
+
+        assert_eq!(
+            tokens(&Regex::new("\\s+").unwrap(), line),
+            Regex::new("\\s+").unwrap().split(line).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            tokens(&RegexFind::new(Regex::new("\\w+").unwrap()), line),
+            vec!["a", "b", "1", "c"]
+        );
+
+        // bytes which aren't UTF-8 are neither separators nor words
+        let line: &[u8] = b"a \xff b=\xfe1";
+        assert_eq!(
+            byte_tokens(&Regex::new("\\s+").unwrap(), line),
+            vec![&b"a"[..], b"\xff", b"b=\xfe1"]
+        );
+        assert_eq!(
+            byte_tokens(&RegexFind::new(Regex::new("\\w+").unwrap()), line),
+            vec![&b"a"[..], b"b", b"1"]
+        );
+    }
+
+    #[test]
+    fn test_delimiters_match_regex_split() {
+        let delimiters = Delimiters::new(" ,").unwrap();
+        let regex = Regex::new(&delimiters.separator_regex()).unwrap();
+
+        for line in &["", " ", "a", "a b", " a,, b ", "a ,b\tc"] {
+            assert_eq!(
+                tokens(&delimiters, line),
+                regex.split(line).collect::<Vec<_>>(),
+                "{:?}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn test_delimiters_must_be_ascii() {
+        assert!(Delimiters::new(" ,").is_ok());
+        assert!(Delimiters::new(" \u{a0}").is_err());
+    }
+
+    #[test]
+    fn test_quote_aware() {
+        let tokenizer = QuoteAware::default();
+
+        assert_eq!(
+            tokens(
+                &tokenizer,
+                r#"[2021-01-01 10:00] GET "/a b \" c" [x [y z]] done"#
+            ),
+            vec![
+                "[2021-01-01 10:00]",
+                "GET",
+                r#""/a b \" c""#,
+                "[x [y z]]",
+                "done"
+            ]
+        );
+        assert_eq!(tokens(&tokenizer, "a \"b c"), vec!["a", "\"b c"]);
+    }
+
+    #[test]
+    fn test_keep_delimiters() {
+        let tokenizer = KeepDelimiters::punctuation(Delimiters::whitespace());
+
+        assert_eq!(
+            tokens(&tokenizer, " user=123 path=/a/b: x"),
+            vec!["", "user", "=", "123", "path", "=", "/", "a", "/", "b", ":", "x"]
+        );
+        assert_eq!(
+            byte_tokens(&tokenizer, b"user=\xff x"),
+            vec![&b"user"[..], b"=", b"\xff", b"x"]
+        );
+    }
+
+    #[test]
+    fn test_keep_delimiters_aligns_values() {
+        let mut clusterer = Clusterer::new(
+            ClustererOptions::default().with_max_dist(0.3),
+            KeepDelimiters::punctuation(Delimiters::whitespace()),
+        );
+        for line in &["login user=123 from=a", "login user=456 from=a"] {
+            clusterer.process_line(line);
+        }
+
+        let clusters = clusterer.all_clusters();
+        assert_eq!(clusters.len(), 1);
+        assert_eq!(clusters[0].to_string(), "2 login user=--- from=a");
+
+        let regex = clusters[0].pattern.to_regex(clusterer.tokenizer()).unwrap();
+        assert!(regex.is_match("login user=789 from=a"));
+        assert!(!regex.is_match("login user 789 from=a"));
+    }
+
+    const HEADER: &str = r"^(?P<date>\S+) (?P<level>\w+) (?P<msg>.*)$";
+
+    #[test]
+    fn test_message_regex() {
+        let tokenizer =
+            MessageRegex::new(Regex::new(HEADER).unwrap(), Delimiters::whitespace()).unwrap();
+
+        assert_eq!(
+            tokens(&tokenizer, "081109 INFO block 1 served"),
+            vec!["block", "1", "served"]
+        );
+        assert_eq!(
+            tokens(&tokenizer, "unmatched line"),
+            vec!["unmatched", "line"]
+        );
+
+        let line = "081109 INFO block 1 served";
+        let mut fields = Vec::new();
+        tokenizer.tokenize_with_fields(line, &mut |_| {}, &mut |index, value| {
+            fields.push((tokenizer.field_name(index), &line[value]))
+        });
+        assert_eq!(fields, vec![("date", "081109"), ("level", "INFO")]);
+
+        assert_eq!(
+            byte_tokens(&tokenizer, b"0811\xff09 INFO block \xfe served"),
+            vec![&b"block"[..], b"\xfe", b"served"]
+        );
+        assert_eq!(
+            byte_tokens(&tokenizer, b"081109 INFO block \xe2\x82"),
+            vec![&b"block"[..], b"\xe2\x82"]
+        );
+
+        assert!(
+            MessageRegex::new(Regex::new("(?P<x>.*)").unwrap(), Delimiters::whitespace()).is_err()
+        );
+    }
+
+    #[test]
+    fn test_message_regex_dimensions() {
+        let mut clusterer = Clusterer::new(
+            ClustererOptions::default().with_max_dist(0.5),
+            MessageRegex::new(Regex::new(HEADER).unwrap(), Delimiters::whitespace()).unwrap(),
+        );
+        for line in &[
+            "081109 INFO served block 1",
+            "081110 WARN served block 2",
+            "081110 INFO served block 3\n",
+        ] {
+            clusterer.process_line(line);
+        }
+
+        let clusters = clusterer.all_clusters();
+        assert_eq!(clusters.len(), 1);
+        assert_eq!(clusters[0].to_string(), "3 served block ---");
+
+        let level = &clusters[0].dimensions["level"];
+        assert_eq!(level.by_count(), vec![("INFO", 2), ("WARN", 1)]);
+        assert_eq!(clusters[0].dimensions["date"].values["081110"], 2);
+
+        let regex = clusters[0].pattern.to_regex(clusterer.tokenizer()).unwrap();
+        assert!(regex.is_match("081111 ERROR served block 4"));
+    }
+
+    #[test]
+    fn test_bytes_regex() {
+        let tokenizer = BytesRegex(regex::bytes::Regex::new("\\s+").unwrap());
+        // a lone 0xA0 is not a no-break space, since it isn't valid UTF-8
+        let line: &[u8] = b"a\xa0b  \xff c";
+
+        assert_eq!(
+            byte_tokens(&tokenizer, line),
+            vec![&b"a\xa0b"[..], b"\xff", b"c"]
+        );
+        assert_eq!(tokens(&tokenizer, "x y"), vec!["x", "y"]);
+
+        let mut clusterer = Clusterer::new(ClustererOptions::default(), tokenizer);
+        for line in &[b"x \xfe\n", b"x \xff\n", b"x \xff\n"] {
+            clusterer.process_bytes(*line);
+        }
+
+        let counts: Vec<_> = clusterer.all_clusters().iter().map(|c| c.count).collect();
+        assert_eq!(counts, vec![1, 2]);
+    }
+}